@@ -0,0 +1,27 @@
+SELECT
+    (
+        SELECT count(*)
+        FROM tags
+        WHERE tags.name LIKE $1 ESCAPE E'\\'
+          AND ($2::smallint IS NULL OR tags.category = $2)
+          AND ($4::smallint[] IS NULL OR tags.category = ANY($4))
+          AND tags.post_count >= $3
+    )
+    +
+    (
+        SELECT count(DISTINCT name)
+        FROM (
+            SELECT tags.name
+            FROM tag_aliases
+            INNER JOIN tags ON tags.name = tag_aliases.consequent_name
+            WHERE tag_aliases.antecedent_name LIKE $1 ESCAPE E'\\'
+              AND tag_aliases.status IN ('active', 'processing', 'queued')
+              AND tag_aliases.post_count > 0
+              AND tags.name NOT LIKE $1 ESCAPE E'\\'
+              AND ($2::smallint IS NULL OR tags.category = $2)
+              AND ($4::smallint[] IS NULL OR tags.category = ANY($4))
+              AND tags.post_count >= $3
+            ORDER BY tags.post_count, length(tag_aliases.antecedent_name) DESC
+            LIMIT 50
+        ) pre_limited
+    ) AS total
@@ -0,0 +1 @@
+SELECT count(*) AS total FROM "tags" WHERE (tags.name % $1) AND ($2::smallint IS NULL OR tags.category = $2) AND ($4::smallint[] IS NULL OR tags.category = ANY($4)) AND (tags.post_count >= $3)
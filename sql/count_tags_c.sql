@@ -0,0 +1 @@
+SELECT count(*) AS total FROM "tags" WHERE ($1 <% tags.name) AND ($2::smallint IS NULL OR tags.category = $2) AND ($4::smallint[] IS NULL OR tags.category = ANY($4)) AND (tags.post_count >= $3) AND (word_similarity($1, tags.name) >= $5::real)
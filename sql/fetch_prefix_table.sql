@@ -0,0 +1,14 @@
+SELECT
+    tags.id,
+    tags.name,
+    tags.post_count,
+    tags.category,
+    prefixes.antecedent_name
+FROM {table} prefixes
+INNER JOIN tags ON tags.id = prefixes.tag_id
+WHERE prefixes.prefix = $1
+  AND ($3::smallint IS NULL OR tags.category = $3)
+  AND ($5::smallint[] IS NULL OR tags.category = ANY($5))
+  AND tags.post_count >= $4
+ORDER BY tags.post_count DESC, tags.name, tags.id
+LIMIT $2
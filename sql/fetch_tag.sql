@@ -0,0 +1 @@
+SELECT tags.id, tags.name, tags.post_count, tags.category, null AS antecedent_name FROM "tags" WHERE (tags.name = $1) LIMIT 1
@@ -5,12 +5,14 @@
         tags.post_count,
         tags.category,
         NULL AS antecedent_name,
-        length(tags.name) AS name_length
+        CASE WHEN $4::text = 'name' THEN tags.name END AS sort_name
     FROM tags
     WHERE tags.name LIKE $1 ESCAPE E'\\'
-      AND tags.post_count > 0
-    ORDER BY tags.post_count DESC
-    LIMIT 10
+      AND ($3::smallint IS NULL OR tags.category = $3)
+      AND ($7::smallint[] IS NULL OR tags.category = ANY($7))
+      AND tags.post_count >= $5
+    ORDER BY sort_name, tags.post_count DESC, tags.name, tags.id
+    LIMIT $2::bigint + $6::bigint
 )
 UNION ALL
 (
@@ -20,7 +22,7 @@ UNION ALL
         post_count,
         category,
         antecedent_name,
-        length(antecedent_name) AS name_length
+        CASE WHEN $4::text = 'name' THEN name END AS sort_name
     FROM (
         SELECT DISTINCT ON (name)
             id,
@@ -41,13 +43,16 @@ UNION ALL
               AND tag_aliases.status IN ('active', 'processing', 'queued')
               AND tag_aliases.post_count > 0
               AND tags.name NOT LIKE $1 ESCAPE E'\\'
+              AND ($3::smallint IS NULL OR tags.category = $3)
+              AND ($7::smallint[] IS NULL OR tags.category = ANY($7))
+              AND tags.post_count >= $5
             ORDER BY tags.post_count, length(tag_aliases.antecedent_name) DESC
             LIMIT 50
         ) pre_limited
         ORDER BY name, post_count DESC
     ) deduped_aliases
-    ORDER BY post_count DESC
-    LIMIT 10
+    ORDER BY sort_name, post_count DESC, name, id
+    LIMIT $2::bigint + $6::bigint
 )
-ORDER BY name_length, post_count DESC
-LIMIT 10
+ORDER BY sort_name, post_count DESC, name, id
+LIMIT $2 OFFSET $6
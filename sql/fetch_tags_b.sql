@@ -1 +1 @@
-SELECT tags.id, tags.name, tags.post_count, tags.category, null AS antecedent_name FROM "tags" WHERE (tags.name % $1) AND (tags.post_count > 0) ORDER BY trunc(3 * similarity(name, $1)) DESC, post_count DESC, name DESC LIMIT 10
+SELECT * FROM (SELECT tags.id, tags.name, tags.post_count, tags.category, null AS antecedent_name, CASE WHEN $7::boolean THEN (CASE WHEN tags.name LIKE $8 ESCAPE E'\\' THEN 3 ELSE 0 END) + log(greatest(tags.post_count, 1)) END AS score FROM "tags" WHERE (tags.name % $1) AND ($3::smallint IS NULL OR tags.category = $3) AND ($9::smallint[] IS NULL OR tags.category = ANY($9)) AND (tags.post_count >= $5) ORDER BY CASE WHEN $4::text = 'name' THEN name END, score DESC NULLS LAST, trunc(3 * similarity(name, $1)) DESC, post_count DESC, name, id LIMIT $2 OFFSET $6) matches ORDER BY CASE WHEN $4::text = 'name' THEN name END, score DESC NULLS LAST, post_count DESC, name, id
@@ -0,0 +1 @@
+SELECT * FROM (SELECT tags.id, tags.name, tags.post_count, tags.category, null AS antecedent_name, CASE WHEN $7::boolean THEN (CASE WHEN tags.name LIKE $8 ESCAPE E'\\' THEN 3 ELSE 0 END) + log(greatest(tags.post_count, 1)) END AS score FROM "tags" WHERE ($1 <% tags.name) AND ($3::smallint IS NULL OR tags.category = $3) AND ($9::smallint[] IS NULL OR tags.category = ANY($9)) AND (tags.post_count >= $5) AND (word_similarity($1, tags.name) >= $10::real) ORDER BY CASE WHEN $4::text = 'name' THEN name END, score DESC NULLS LAST, word_similarity($1, tags.name) DESC, post_count DESC, name, id LIMIT $2 OFFSET $6) matches ORDER BY CASE WHEN $4::text = 'name' THEN name END, score DESC NULLS LAST, post_count DESC, name, id
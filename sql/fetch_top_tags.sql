@@ -0,0 +1 @@
+SELECT tags.name FROM "tags" WHERE (tags.post_count > 0) ORDER BY post_count DESC LIMIT $1
@@ -1,34 +1,375 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::HttpMessage;
 use actix_web::{
-    error, get,
+    delete, error, get,
     http::header,
     http::StatusCode,
-    middleware::DefaultHeaders,
+    middleware::{Compress, DefaultHeaders},
+    post, routes,
     web::{self, Data},
-    HttpResponse, HttpResponseBuilder,
+    App, HttpRequest, HttpResponse, HttpResponseBuilder,
 };
+use async_trait::async_trait;
 use deadpool_postgres::{Pool, Runtime};
 use derive_more::{Display, Error, From};
-use log::error;
+use log::{debug, error, info, warn};
+use models::{AnnotatedTag, Format, Language, Order, Tag};
 use moka::future::Cache;
-use serde::Deserialize;
+use moka::ops::compute::{CompResult, Op};
+use moka::Expiry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tracing::Instrument;
 
 mod config {
-    use serde::Deserialize;
+    use serde::{Deserialize, Deserializer};
 
     #[derive(Deserialize)]
     pub struct Config {
         pub server_addr: String,
         pub pg: deadpool_postgres::Config,
+        #[serde(default = "default_cache_max_capacity")]
+        pub cache_max_capacity: u64,
+        #[serde(default = "default_cache_ttl_secs")]
+        pub cache_ttl_secs: u64,
+        #[serde(default = "default_cache_empty_ttl_secs")]
+        pub cache_empty_ttl_secs: u64,
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub allowed_origins: Vec<String>,
+        pub content_security_policy: Option<String>,
+        pub referrer_policy: Option<String>,
+        #[serde(default)]
+        pub rate_limit_per_sec: f64,
+        #[serde(default = "default_rate_limit_burst")]
+        pub rate_limit_burst: u32,
+        #[serde(default)]
+        pub short_prefix_empty: bool,
+        #[serde(default)]
+        pub min_post_count_default: i32,
+        #[serde(default)]
+        pub min_similarity: f64,
+        #[serde(default)]
+        pub include_zero_count: bool,
+        #[serde(default = "default_shutdown_timeout_secs")]
+        pub shutdown_timeout_secs: u64,
+        #[serde(default)]
+        pub trigram_fallback: bool,
+        #[serde(default)]
+        pub weighted_ranking: bool,
+        #[serde(default)]
+        pub fill_from_fallback: bool,
+        #[serde(default)]
+        pub warm_cache: bool,
+        #[serde(default = "default_warm_cache_top_n")]
+        pub warm_cache_top_n: i64,
+        pub admin_token: Option<String>,
+        #[serde(default = "default_statement_timeout_ms")]
+        pub statement_timeout_ms: u64,
+        #[serde(default = "default_application_name")]
+        pub application_name: String,
+        #[serde(default)]
+        pub log_format: crate::logging::LogFormat,
+        #[serde(default = "default_response_max_age_secs")]
+        pub response_max_age_secs: u64,
+        #[serde(default)]
+        pub response_max_age_from_cache_ttl: bool,
+        #[serde(default)]
+        pub cache_soft_ttl_secs: u64,
+        /// `host` or `host:port` of read replicas, which share `pg`'s other settings.
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub pg_replicas: Vec<String>,
+        #[serde(default = "default_startup_db_attempts")]
+        pub startup_db_attempts: u32,
+        #[serde(default = "default_startup_db_retry_ms")]
+        pub startup_db_retry_ms: u64,
+        #[serde(default)]
+        pub pg_tls: bool,
+        pub pg_tls_ca_path: Option<String>,
+        pub tls_cert_path: Option<String>,
+        pub tls_key_path: Option<String>,
+        pub workers: Option<usize>,
+        /// Overrides `pg.pool.max_size`, for each of the primary and replicas.
+        pub pg_pool_max_size: Option<usize>,
+        #[serde(default = "default_min_prefix_len")]
+        pub min_prefix_len: usize,
+        #[serde(default = "default_max_prefix_len")]
+        pub max_prefix_len: usize,
+        #[serde(default = "default_pool_acquire_timeout_ms")]
+        pub pool_acquire_timeout_ms: u64,
+        pub word_separator: Option<char>,
+        #[serde(default)]
+        pub lowercase: crate::models::Lowercase,
+        #[serde(default)]
+        pub max_concurrent_queries: usize,
+        #[serde(default)]
+        pub max_in_flight_requests: usize,
+        #[serde(default = "default_query_permit_timeout_ms")]
+        pub query_permit_timeout_ms: u64,
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub trusted_proxies: Vec<String>,
+        #[serde(default, deserialize_with = "comma_separated")]
+        pub allowed_categories: Vec<String>,
+        #[serde(default)]
+        pub empty_as_204: bool,
+        #[serde(default)]
+        pub soft_errors: bool,
+        #[serde(default)]
+        pub match_stage_header: bool,
+        #[serde(default)]
+        pub allow_debug: bool,
+        pub sql_fetch_a_path: Option<String>,
+        pub sql_fetch_b_path: Option<String>,
+        #[serde(default)]
+        pub max_cached_bytes: usize,
+        #[serde(default)]
+        pub cache_max_bytes: u64,
+        #[serde(default)]
+        pub base_path: String,
+        #[serde(default)]
+        pub probes_at_root: bool,
+        #[serde(default = "default_max_statement_timeout_ms")]
+        pub max_statement_timeout_ms: u64,
+        pub prefix_table: Option<String>,
+        #[serde(default = "default_prefix_table_max_len")]
+        pub prefix_table_max_len: usize,
+        /// Part of every cache key, so changing it leaves older entries unused.
+        #[serde(default)]
+        pub cache_version: String,
+    }
+
+    fn default_prefix_table_max_len() -> usize {
+        3
+    }
+
+    fn default_rate_limit_burst() -> u32 {
+        20
+    }
+
+    fn default_shutdown_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_warm_cache_top_n() -> i64 {
+        1_000
+    }
+
+    fn default_application_name() -> String {
+        "autocompleted".to_owned()
+    }
+
+    fn default_statement_timeout_ms() -> u64 {
+        3_000
+    }
+
+    fn default_max_statement_timeout_ms() -> u64 {
+        10_000
+    }
+
+    fn default_response_max_age_secs() -> u64 {
+        7 * 24 * 60 * 60
+    }
+
+    fn default_query_permit_timeout_ms() -> u64 {
+        100
+    }
+
+    fn default_pool_acquire_timeout_ms() -> u64 {
+        2_000
+    }
+
+    fn default_min_prefix_len() -> usize {
+        3
+    }
+
+    fn default_max_prefix_len() -> usize {
+        100
+    }
+
+    fn default_startup_db_attempts() -> u32 {
+        8
+    }
+
+    fn default_startup_db_retry_ms() -> u64 {
+        500
+    }
+
+    fn comma_separated<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<String>, D::Error> {
+        Ok(String::deserialize(deserializer)?
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn default_cache_max_capacity() -> u64 {
+        15_000
+    }
+
+    fn default_cache_ttl_secs() -> u64 {
+        6 * 60 * 60
+    }
+
+    fn default_cache_empty_ttl_secs() -> u64 {
+        5 * 60
     }
 
     impl Config {
+        /// The `max-age` to send on results, optionally tied to the cache TTL so
+        /// clients don't hold on to results longer than we do.
+        pub fn response_max_age_secs(&self) -> u64 {
+            if self.response_max_age_from_cache_ttl {
+                self.cache_ttl_secs
+            } else {
+                self.response_max_age_secs
+            }
+        }
+
+        /// The path every route is mounted under, as `/e621` for any of `e621`,
+        /// `/e621` or `/e621/`, or empty to mount them at the root.
+        pub fn base_path(&self) -> String {
+            match self.base_path.trim_matches('/') {
+                "" => String::new(),
+                x => format!("/{x}"),
+            }
+        }
+
+        /// `pg` pointed at each of the configured read replicas.
+        pub fn replica_configs(&self) -> Vec<deadpool_postgres::Config> {
+            self.pg_replicas
+                .iter()
+                .map(|replica| {
+                    let mut pg = self.pg.clone();
+                    let (host, port) = match replica.rsplit_once(':') {
+                        Some((host, port)) if port.parse::<u16>().is_ok() => {
+                            (host, port.parse().ok())
+                        }
+                        _ => (replica.as_str(), self.pg.port),
+                    };
+                    pg.host = Some(host.to_owned());
+                    pg.hosts = None;
+                    pg.port = port;
+                    pg
+                })
+                .collect()
+        }
+
         pub fn from_env() -> Result<Self, config::ConfigError> {
+            Self::from_environment(config::Environment::default())
+        }
+
+        /// Like [`Config::from_env`], but from `vars` rather than the process's
+        /// environment.
+        #[cfg(test)]
+        pub fn from_vars(vars: &[(&str, &str)]) -> Self {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            Self::from_environment(config::Environment::default().source(Some(vars)))
+                .expect("Failed to load test configuration")
+        }
+
+        fn from_environment(env: config::Environment) -> Result<Self, config::ConfigError> {
             config::Config::builder()
-                .add_source(config::Environment::default().separator("__"))
+                .add_source(env.separator("__"))
                 .build()?
                 .try_deserialize()
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const REQUIRED: [(&str, &str); 2] = [("SERVER_ADDR", "127.0.0.1:0"), ("PG__DBNAME", "ac")];
+
+        #[test]
+        fn unset_settings_keep_their_defaults() {
+            let config = Config::from_vars(&REQUIRED);
+            assert_eq!(config.server_addr, "127.0.0.1:0");
+            assert_eq!(config.pg.dbname.as_deref(), Some("ac"));
+            assert_eq!(config.cache_max_capacity, 15_000);
+            assert_eq!(config.cache_ttl_secs, 6 * 60 * 60);
+            assert_eq!(config.cache_empty_ttl_secs, 5 * 60);
+            assert_eq!(config.cache_max_bytes, 0);
+            assert_eq!(config.min_prefix_len, 3);
+            assert_eq!(config.max_prefix_len, 100);
+            assert_eq!(config.statement_timeout_ms, 3_000);
+            assert!(config.allowed_origins.is_empty());
+            assert!(!config.short_prefix_empty);
+        }
+
+        #[test]
+        fn settings_are_read_from_the_environment() {
+            let mut vars = REQUIRED.to_vec();
+            vars.extend([
+                ("CACHE_MAX_CAPACITY", "500"),
+                ("CACHE_TTL_SECS", "60"),
+                ("ALLOWED_ORIGINS", "https://a.example, https://b.example,"),
+                ("PG__HOST", "db.internal"),
+                ("PG__POOL__MAX_SIZE", "7"),
+            ]);
+            let config = Config::from_vars(&vars);
+            assert_eq!(config.cache_max_capacity, 500);
+            assert_eq!(config.cache_ttl_secs, 60);
+            assert_eq!(
+                config.allowed_origins,
+                ["https://a.example", "https://b.example"]
+            );
+            assert_eq!(config.pg.host.as_deref(), Some("db.internal"));
+            assert_eq!(config.pg.pool.map(|x| x.max_size), Some(7));
+        }
+
+        #[test]
+        fn workers_default_to_actix_own_choice() {
+            let config = Config::from_vars(&REQUIRED);
+            assert_eq!(config.workers, None);
+            assert_eq!(config.shutdown_timeout_secs, 30);
+            let mut vars = REQUIRED.to_vec();
+            vars.extend([("WORKERS", "3"), ("SHUTDOWN_TIMEOUT_SECS", "5")]);
+            let config = Config::from_vars(&vars);
+            assert_eq!(config.workers, Some(3));
+            assert_eq!(config.shutdown_timeout_secs, 5);
+        }
+
+        #[test]
+        fn postgres_tls_is_off_unless_enabled() {
+            let config = Config::from_vars(&REQUIRED);
+            assert!(!config.pg_tls);
+            assert!(config.pg_tls_ca_path.is_none());
+            let mut vars = REQUIRED.to_vec();
+            vars.extend([("PG_TLS", "true"), ("PG_TLS_CA_PATH", "/etc/ssl/rds.pem")]);
+            let config = Config::from_vars(&vars);
+            assert!(config.pg_tls);
+            assert_eq!(config.pg_tls_ca_path.as_deref(), Some("/etc/ssl/rds.pem"));
+        }
+
+        #[test]
+        fn https_is_configured_by_both_paths() {
+            let config = Config::from_vars(&REQUIRED);
+            assert!(config.tls_cert_path.is_none() && config.tls_key_path.is_none());
+            let mut vars = REQUIRED.to_vec();
+            vars.extend([
+                ("TLS_CERT_PATH", "/etc/tls/cert.pem"),
+                ("TLS_KEY_PATH", "/etc/tls/key.pem"),
+            ]);
+            let config = Config::from_vars(&vars);
+            assert_eq!(config.tls_cert_path.as_deref(), Some("/etc/tls/cert.pem"));
+            assert_eq!(config.tls_key_path.as_deref(), Some("/etc/tls/key.pem"));
+        }
+    }
 }
 
 mod models {
@@ -44,186 +385,7606 @@ mod models {
         pub category: i16,
         pub antecedent_name: Option<String>,
     }
-}
 
-mod db {
-    use deadpool_postgres::Client;
-    use tokio_pg_mapper::FromTokioPostgresRow;
+    /// How results are sorted. Ties are always broken by name.
+    #[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Order {
+        /// Most-used tags first.
+        #[default]
+        Count,
+        /// Alphabetical by name.
+        Name,
+    }
 
-    use crate::models::Tag;
+    impl Order {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                Order::Count => "count",
+                Order::Name => "name",
+            }
+        }
+    }
 
-    fn escape_like(stuff: &str) -> String {
-        stuff
-            .replace('%', "\\%")
-            .replace('_', "\\_")
-            .replace('*', "%")
-            .replace("\\*", "*")
+    /// How a result set is serialized.
+    #[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Format {
+        /// A JSON array of tag objects.
+        #[default]
+        Json,
+        /// One tag object per line.
+        Ndjson,
+        /// `{"fields": [...], "rows": [[...], ...]}`, naming each field once
+        /// rather than once per tag.
+        Compact,
     }
 
-    pub async fn get_tags(
-        client: &Client,
-        tag_prefix: &String,
-    ) -> Result<Vec<Tag>, tokio_postgres::Error> {
-        let escape_prefix = escape_like(&(tag_prefix.to_owned() + "*"));
-        let stmt = client
-            .prepare_cached(include_str!("../sql/fetch_tags_a.sql"))
-            .await?;
-        let rows = client
-            .query(&stmt, &[&escape_prefix])
-            .await?
+    impl Format {
+        pub fn content_type(self) -> &'static str {
+            match self {
+                Format::Json => "application/json; charset=utf-8",
+                Format::Ndjson => "application/x-ndjson; charset=utf-8",
+                Format::Compact => "application/json; charset=utf-8",
+            }
+        }
+
+        pub fn serialize<T: Serialize>(self, items: &[T]) -> String {
+            match self {
+                Format::Json => serde_json::to_string(items).unwrap_or_else(|_| "[]".to_string()),
+                Format::Ndjson => items
+                    .iter()
+                    .filter_map(|x| serde_json::to_string(x).ok())
+                    .map(|x| x + "\n")
+                    .collect(),
+                Format::Compact => serialize_compact(items),
+            }
+        }
+    }
+
+    fn serialize_compact<T: Serialize>(items: &[T]) -> String {
+        use serde_json::{Map, Value};
+
+        let objects = items
             .iter()
-            .map(|row| Tag::from_row_ref(row).unwrap())
-            .collect::<Vec<Tag>>();
-        if !rows.is_empty() {
-            return Ok(rows);
+            .filter_map(|x| match serde_json::to_value(x) {
+                Ok(Value::Object(x)) => Some(x),
+                _ => None,
+            })
+            .collect::<Vec<Map<String, Value>>>();
+        // optional annotations are left out when unset, so take every field
+        // any row has, in the order they first appear
+        let mut fields: Vec<&str> = Vec::new();
+        for key in objects.iter().flat_map(|x| x.keys()) {
+            if !fields.contains(&key.as_str()) {
+                fields.push(key);
+            }
         }
-        let stmt = client
-            .prepare_cached(include_str!("../sql/fetch_tags_b.sql"))
-            .await?;
-        let rows = client
-            .query(&stmt, &[&tag_prefix])
-            .await?
+        let rows = objects
             .iter()
-            .map(|row| Tag::from_row_ref(row).unwrap())
-            .collect::<Vec<Tag>>();
-        Ok(rows)
+            .map(|x| {
+                fields
+                    .iter()
+                    .map(|field| x.get(*field).cloned().unwrap_or(Value::Null))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "fields": fields, "rows": rows }).to_string()
     }
-}
 
-struct AutocompleteState {
-    pool: Pool,
-    cache: Cache<String, String>,
+    /// How prefixes are lowercased.
+    #[derive(Clone, Copy, Default, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Lowercase {
+        /// Unicode's full lowercasing.
+        #[default]
+        Unicode,
+        /// ASCII letters lowercased as ASCII and a dotted capital I as a plain
+        /// `i`, instead of as an `i` followed by a combining dot that the ASCII
+        /// names it was meant for don't have. Other scripts are as `Unicode`.
+        Ascii,
+    }
+
+    impl Lowercase {
+        pub fn apply(self, s: &str) -> String {
+            match self {
+                Lowercase::Unicode => s.to_lowercase(),
+                Lowercase::Ascii => {
+                    let mut lowered = String::with_capacity(s.len());
+                    for x in s.chars() {
+                        match x {
+                            '\u{130}' => lowered.push('i'),
+                            x if x.is_ascii() => lowered.push(x.to_ascii_lowercase()),
+                            x => lowered.extend(x.to_lowercase()),
+                        }
+                    }
+                    lowered
+                }
+            }
+        }
+    }
+
+    /// A client language we have a naming preference for, negotiated from
+    /// `Accept-Language`.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Language {
+        /// Prefers names in native script.
+        Ja,
+        /// Prefers matches through romanized aliases.
+        En,
+    }
+
+    impl Language {
+        /// The most preferred supported language in an `Accept-Language`
+        /// header, if any.
+        pub fn negotiate(header: &str) -> Option<Self> {
+            let mut best: Option<(f32, Language)> = None;
+            for range in header.split(',') {
+                let mut parts = range.split(';').map(str::trim);
+                let tag = parts.next().unwrap_or_default();
+                let q = parts
+                    .find_map(|x| x.strip_prefix("q="))
+                    .map_or(Some(1.0), |x| x.parse::<f32>().ok())
+                    .unwrap_or(0.0);
+                let primary = tag.split('-').next().unwrap_or_default();
+                let language = if primary.eq_ignore_ascii_case("ja") {
+                    Language::Ja
+                } else if primary.eq_ignore_ascii_case("en") {
+                    Language::En
+                } else {
+                    continue;
+                };
+                if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                    best = Some((q, language));
+                }
+            }
+            best.map(|(_, x)| x)
+        }
+
+        fn prefers(self, tag: &Tag) -> bool {
+            match self {
+                Language::Ja => !tag.name.is_ascii(),
+                Language::En => tag.antecedent_name.as_deref().is_some_and(str::is_ascii),
+            }
+        }
+
+        /// Moves preferred tags ahead of adjacent ones with the same post
+        /// count, leaving the order otherwise alone. Only meant for tags
+        /// ordered by post count and then name, where this just replaces the
+        /// name as the tiebreak.
+        pub fn rank(self, tags: &mut [Tag]) {
+            for ties in tags.chunk_by_mut(|a, b| a.post_count == b.post_count) {
+                ties.sort_by_key(|x| !self.prefers(x));
+            }
+        }
+    }
+
+    /// Every field a result can have, in serialization order.
+    pub const FIELDS: &[&str] = &[
+        "id",
+        "name",
+        "post_count",
+        "category",
+        "antecedent_name",
+        "matched_name",
+        "is_alias",
+        "category_name",
+        "exact",
+    ];
+
+    /// The known fields in a comma-separated list, in canonical order, or
+    /// `None` if it names none.
+    pub fn parse_fields(list: &str) -> Option<Vec<&'static str>> {
+        let requested = list.split(',').map(str::trim).collect::<Vec<_>>();
+        let fields = FIELDS
+            .iter()
+            .copied()
+            .filter(|x| requested.contains(x))
+            .collect::<Vec<_>>();
+        (!fields.is_empty()).then_some(fields)
+    }
+
+    /// Serializes each item keeping only `fields`.
+    pub fn project<T: Serialize>(items: &[T], fields: &[&str]) -> Vec<serde_json::Value> {
+        items
+            .iter()
+            .filter_map(|x| match serde_json::to_value(x) {
+                Ok(serde_json::Value::Object(mut x)) => {
+                    x.retain(|k, _| fields.contains(&k.as_str()));
+                    Some(serde_json::Value::Object(x))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Tag category names, indexed by category number.
+    pub const CATEGORY_NAMES: &[&str] = &[
+        "general",
+        "artist",
+        "contributor",
+        "copyright",
+        "character",
+        "species",
+        "invalid",
+        "meta",
+        "lore",
+    ];
+
+    pub fn category_id(name: &str) -> Option<i16> {
+        CATEGORY_NAMES
+            .iter()
+            .position(|x| x.eq_ignore_ascii_case(name))
+            .and_then(|x| i16::try_from(x).ok())
+    }
+
+    pub fn category_name(category: i16) -> Option<&'static str> {
+        usize::try_from(category)
+            .ok()
+            .and_then(|x| CATEGORY_NAMES.get(x))
+            .copied()
+    }
+
+    /// A tag with optional annotations. Unrequested annotations are left out,
+    /// so with none requested this serializes exactly like [`Tag`].
+    #[derive(Serialize)]
+    pub struct AnnotatedTag<'a> {
+        #[serde(flatten)]
+        pub tag: &'a Tag,
+        /// The name the search actually matched, so alias hits can be told
+        /// apart from direct hits without inspecting `antecedent_name`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub matched_name: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub is_alias: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub category_name: Option<&'static str>,
+        /// Whether the name is exactly the prefix, rather than merely starting
+        /// with it.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub exact: Option<bool>,
+    }
+
+    impl<'a> AnnotatedTag<'a> {
+        /// With `exact_prefix`, the tag is also marked with whether its name is
+        /// that prefix.
+        pub fn new(
+            tag: &'a Tag,
+            resolve_aliases: bool,
+            category_names: bool,
+            exact_prefix: Option<&str>,
+        ) -> Self {
+            AnnotatedTag {
+                tag,
+                matched_name: resolve_aliases
+                    .then(|| tag.antecedent_name.as_deref().unwrap_or(&tag.name)),
+                is_alias: resolve_aliases.then_some(tag.antecedent_name.is_some()),
+                category_name: category_names
+                    .then(|| category_name(tag.category))
+                    .flatten(),
+                exact: exact_prefix.map(|x| tag.name == x),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn negotiate_picks_the_highest_quality() {
+            assert!(matches!(Language::negotiate("ja"), Some(Language::Ja)));
+            assert!(matches!(
+                Language::negotiate("en-US,en;q=0.9"),
+                Some(Language::En)
+            ));
+            assert!(matches!(
+                Language::negotiate("en;q=0.5, ja-JP;q=0.8"),
+                Some(Language::Ja)
+            ));
+            // the first of equally preferred ones
+            assert!(matches!(Language::negotiate("EN, ja"), Some(Language::En)));
+            assert!(matches!(
+                Language::negotiate("fr, de;q=0.9, ja;q=0.1"),
+                Some(Language::Ja)
+            ));
+            assert!(Language::negotiate("fr, de").is_none());
+            assert!(Language::negotiate("ja;q=0").is_none());
+            assert!(Language::negotiate("ja;q=bogus").is_none());
+            assert!(Language::negotiate("").is_none());
+        }
+
+        fn tag(id: i32, name: &str, post_count: i32, antecedent_name: Option<&str>) -> Tag {
+            Tag {
+                id,
+                name: name.to_owned(),
+                post_count,
+                category: 0,
+                antecedent_name: antecedent_name.map(str::to_owned),
+            }
+        }
+
+        fn ids(tags: &[Tag]) -> Vec<i32> {
+            tags.iter().map(|x| x.id).collect()
+        }
+
+        #[test]
+        fn compact_has_the_same_values_as_json() {
+            use serde_json::{json, Value};
+            let tags = [tag(1, "fur", 100, None), tag(2, "fox", 50, Some("vulpine"))];
+            let objects: Value = serde_json::from_str(&Format::Json.serialize(&tags)).unwrap();
+            let compact: Value = serde_json::from_str(&Format::Compact.serialize(&tags)).unwrap();
+            assert_eq!(
+                compact,
+                json!({
+                    "fields": ["id", "name", "post_count", "category", "antecedent_name"],
+                    "rows": [[1, "fur", 100, 0, null], [2, "fox", 50, 0, "vulpine"]],
+                })
+            );
+            let fields = compact["fields"].as_array().unwrap();
+            for (object, row) in objects
+                .as_array()
+                .unwrap()
+                .iter()
+                .zip(compact["rows"].as_array().unwrap())
+            {
+                for (field, value) in fields.iter().zip(row.as_array().unwrap()) {
+                    assert_eq!(&object[field.as_str().unwrap()], value);
+                }
+            }
+            assert_eq!(
+                Format::Compact.serialize::<Tag>(&[]),
+                r#"{"fields":[],"rows":[]}"#
+            );
+        }
+
+        #[test]
+        fn compact_fills_in_annotations_rows_lack() {
+            use serde_json::{json, Value};
+            let (fur, fox) = (tag(1, "fur", 100, None), tag(2, "fox", 50, Some("vulpine")));
+            let annotated = [
+                AnnotatedTag::new(&fur, true, false, None),
+                AnnotatedTag::new(&fox, true, false, None),
+            ];
+            let compact: Value =
+                serde_json::from_str(&Format::Compact.serialize(&annotated)).unwrap();
+            let fields = compact["fields"].as_array().unwrap();
+            let is_alias = fields.iter().position(|x| x == "is_alias").unwrap();
+            assert_eq!(compact["rows"][0][is_alias], json!(false));
+            assert_eq!(compact["rows"][1][is_alias], json!(true));
+            assert!(compact["rows"].as_array().unwrap().iter().all(|x| x
+                .as_array()
+                .unwrap()
+                .len()
+                == fields.len()));
+        }
+
+        #[test]
+        fn rank_only_breaks_ties() {
+            let tags = || {
+                vec![
+                    tag(1, "neko", 10, None),
+                    tag(2, "\u{732b}", 10, None),
+                    tag(3, "inu", 10, Some("dog")),
+                    tag(4, "\u{72ac}", 5, None),
+                    tag(5, "kitsune", 5, None),
+                    // out of post count order, like a fuzzy match would be
+                    tag(6, "\u{72d0}", 10, None),
+                ]
+            };
+            let mut ja = tags();
+            Language::Ja.rank(&mut ja);
+            assert_eq!(ids(&ja), [2, 1, 3, 4, 5, 6]);
+            let mut en = tags();
+            Language::En.rank(&mut en);
+            assert_eq!(ids(&en), [3, 1, 2, 4, 5, 6]);
+        }
+    }
 }
 
-#[derive(Debug, Display, Error, From)]
-enum AutocompleteError {
-    #[display(fmt = "bad request")]
-    BadRequest,
-    #[display(fmt = "internal error")]
-    ServerError,
+/// The OpenAPI 3 description served at `/openapi.json`. Limits, fields and
+/// category names come from the same constants the handlers use.
+mod openapi {
+    use serde_json::{json, Value};
+
+    use crate::models::{CATEGORY_NAMES, FIELDS};
+    use crate::{DEFAULT_LIMIT, MAX_BATCH_SIZE, MAX_LIMIT, MAX_OFFSET, MAX_STREAM_LIMIT};
+
+    fn query_param(name: &str, description: &str, schema: Value) -> Value {
+        json!({
+            "name": name,
+            "in": "query",
+            "description": description,
+            "schema": schema,
+        })
+    }
+
+    fn error(description: &str) -> Value {
+        json!({
+            "description": description,
+            "content": {
+                "application/json": { "schema": { "$ref": "#/components/schemas/Error" } }
+            },
+        })
+    }
+
+    fn results() -> Value {
+        json!({
+            "description": "Matching tags",
+            "headers": {
+                "ETag": { "schema": { "type": "string" } },
+                "X-Total-Count": {
+                    "description": "Total matches across all pages, for paginated searches",
+                    "schema": { "type": "integer" },
+                },
+            },
+            "content": {
+                "application/json": {
+                    "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Tag" } }
+                },
+                "application/x-ndjson": { "schema": { "$ref": "#/components/schemas/Tag" } },
+            },
+        })
+    }
+
+    fn search_responses() -> Value {
+        json!({
+            "200": results(),
+            "204": { "description": "No matches, with `EMPTY_AS_204`" },
+            "304": { "description": "The results match `If-None-Match`" },
+            "400": error("Invalid parameters"),
+            "429": error("Rate limited"),
+            "500": error("Query failed"),
+            "503": error("No database connection available in time"),
+            "504": error("Query timed out"),
+        })
+    }
+
+    fn search() -> Value {
+        let fields = json!({ "type": "string", "enum": FIELDS });
+        json!({
+            "get": {
+                "summary": "Complete a tag prefix",
+                "parameters": [
+                    query_param(
+                        "search[name_matches]",
+                        "The prefix to complete; may contain one `*` wildcard and start with a category qualifier such as `artist:`. Repeated, results are an object keyed by prefix",
+                        json!({ "type": "string" }),
+                    ),
+                    query_param("q", "Alternative name for `search[name_matches]`", json!({ "type": "string" })),
+                    query_param(
+                        "limit",
+                        "Number of results",
+                        json!({ "type": "integer", "minimum": 1, "maximum": MAX_LIMIT, "default": DEFAULT_LIMIT }),
+                    ),
+                    query_param("category", "Only tags in this category", json!({ "type": "integer" })),
+                    query_param(
+                        "order",
+                        "Sort by post count or name",
+                        json!({ "type": "string", "enum": ["count", "name"], "default": "count" }),
+                    ),
+                    query_param(
+                        "offset",
+                        "Skip this many results",
+                        json!({ "type": "integer", "minimum": 0, "maximum": MAX_OFFSET }),
+                    ),
+                    query_param("resolve_aliases", "Add `matched_name` and `is_alias`", json!({ "type": "boolean" })),
+                    query_param("prefix_only", "Skip the fuzzy fallbacks", json!({ "type": "boolean" })),
+                    query_param("min_post_count", "Hide tags with fewer posts", json!({ "type": "integer" })),
+                    query_param(
+                        "min_similarity",
+                        "Word similarity that fuzzy stage C matches need",
+                        json!({ "type": "number", "minimum": 0, "maximum": 1 }),
+                    ),
+                    query_param("category_names", "Add `category_name`", json!({ "type": "boolean" })),
+                    query_param("annotate_exact", "Add `exact`", json!({ "type": "boolean" })),
+                    query_param(
+                        "group_by_category",
+                        "Return an object of result arrays keyed by category instead, only with the `json` format",
+                        json!({ "type": "boolean" }),
+                    ),
+                    query_param("timeout_ms", "Query timeout for this request", json!({ "type": "integer", "minimum": 1 })),
+                    json!({
+                        "name": "fields",
+                        "in": "query",
+                        "description": "Comma-separated fields to keep",
+                        "schema": { "type": "array", "items": fields },
+                        "explode": false,
+                    }),
+                    query_param(
+                        "format",
+                        "Response format",
+                        json!({ "type": "string", "enum": ["json", "ndjson", "compact"], "default": "json" }),
+                    ),
+                    query_param("callback", "JSONP callback name", json!({ "type": "string" })),
+                    query_param(
+                        "nocache",
+                        "With the admin token as a bearer token, query afresh and refresh the cached entry",
+                        json!({ "type": "boolean" }),
+                    ),
+                    query_param(
+                        "debug",
+                        "Return `{\"results\": [...], \"timings\": {...}, \"stage\": ...}` instead, with `ALLOW_DEBUG`",
+                        json!({ "type": "boolean" }),
+                    ),
+                    query_param(
+                        "stream",
+                        &format!("Query afresh and send the results as they're serialized, allowing a `limit` of up to {MAX_STREAM_LIMIT}; only with the `json` or `ndjson` format"),
+                        json!({ "type": "boolean" }),
+                    ),
+                ],
+                "responses": search_responses(),
+            },
+            "post": {
+                "summary": "Complete a tag prefix, with the options as a JSON body",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["prefix"],
+                                "properties": {
+                                    "prefix": { "type": "string" },
+                                    "limit": { "type": "integer", "minimum": 1, "maximum": MAX_LIMIT },
+                                    "category": { "type": "integer" },
+                                    "order": { "type": "string", "enum": ["count", "name"] },
+                                    "offset": { "type": "integer", "minimum": 0, "maximum": MAX_OFFSET },
+                                    "resolve_aliases": { "type": "boolean" },
+                                    "prefix_only": { "type": "boolean" },
+                                    "min_post_count": { "type": "integer" },
+                                    "min_similarity": { "type": "number", "minimum": 0, "maximum": 1 },
+                                    "category_names": { "type": "boolean" },
+                                    "annotate_exact": { "type": "boolean" },
+                                    "group_by_category": { "type": "boolean" },
+                                    "timeout_ms": { "type": "integer", "minimum": 1 },
+                                    "fields": { "type": "array", "items": fields },
+                                    "format": { "type": "string", "enum": ["json", "ndjson", "compact"] },
+                                    "debug": { "type": "boolean" },
+                                    "nocache": { "type": "boolean" },
+                                    "stream": { "type": "boolean" },
+                                },
+                            }
+                        }
+                    },
+                },
+                "responses": search_responses(),
+            },
+        })
+    }
+
+    /// The document for a service mounted at `base_path`, as
+    /// [`Config::base_path`](crate::config::Config::base_path) gives it.
+    pub fn document(base_path: &str) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "autocompleted",
+                "description": "Tag autocomplete",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "servers": [{ "url": if base_path.is_empty() { "/" } else { base_path } }],
+            "paths": {
+                "/": search(),
+                "/autocomplete": search(),
+                "/batch": {
+                    "post": {
+                        "summary": "Complete several prefixes at once",
+                        "requestBody": {
+                            "required": true,
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "maxItems": MAX_BATCH_SIZE,
+                                        "items": { "type": "string" },
+                                    }
+                                }
+                            },
+                        },
+                        "responses": {
+                            "200": {
+                                "description": "Each prefix's results",
+                                "content": {
+                                    "application/json": {
+                                        "schema": {
+                                            "type": "object",
+                                            "additionalProperties": {
+                                                "type": "array",
+                                                "items": { "$ref": "#/components/schemas/Tag" },
+                                            },
+                                        }
+                                    }
+                                },
+                            },
+                            "400": error("Too many or invalid prefixes"),
+                        },
+                    }
+                },
+                "/tags/{name}": {
+                    "get": {
+                        "summary": "Look up a tag by exact name",
+                        "parameters": [{
+                            "name": "name",
+                            "in": "path",
+                            "required": true,
+                            "schema": { "type": "string" },
+                        }],
+                        "responses": {
+                            "200": {
+                                "description": "The tag",
+                                "content": {
+                                    "application/json": {
+                                        "schema": { "$ref": "#/components/schemas/Tag" }
+                                    }
+                                },
+                            },
+                            "404": error("No such tag"),
+                        },
+                    }
+                },
+            },
+            "components": {
+                "schemas": {
+                    "Tag": {
+                        "type": "object",
+                        "required": ["id", "name", "post_count", "category", "antecedent_name"],
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "name": { "type": "string" },
+                            "post_count": { "type": "integer" },
+                            "category": { "type": "integer" },
+                            "antecedent_name": {
+                                "type": "string",
+                                "nullable": true,
+                                "description": "The alias the prefix matched, if it matched one",
+                            },
+                            "matched_name": { "type": "string", "description": "With `resolve_aliases`" },
+                            "is_alias": { "type": "boolean", "description": "With `resolve_aliases`" },
+                            "category_name": {
+                                "type": "string",
+                                "enum": CATEGORY_NAMES,
+                                "description": "With `category_names`",
+                            },
+                            "exact": {
+                                "type": "boolean",
+                                "description": "With `annotate_exact`, whether the name is exactly the prefix",
+                            },
+                        },
+                    },
+                    "Error": {
+                        "type": "object",
+                        "properties": {
+                            "error": { "type": "string" },
+                            "code": { "type": "string", "description": "Stable machine-readable reason, e.g. `PREFIX_TOO_SHORT`" },
+                        },
+                    },
+                },
+            },
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde::de::{self, Deserialize, Visitor};
+
+        /// Stops deserializing as soon as it learns the struct's field names.
+        struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+            type Error = de::value::Error;
+
+            fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+                Err(de::Error::custom("not a struct"))
+            }
+
+            fn deserialize_struct<V: Visitor<'de>>(
+                self,
+                _: &'static str,
+                fields: &'static [&'static str],
+                _: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = fields;
+                Err(de::Error::custom("done"))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+                bytes byte_buf option unit unit_struct newtype_struct seq tuple
+                tuple_struct map enum identifier ignored_any
+            }
+        }
+
+        fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+            let mut fields: &'static [&'static str] = &[];
+            let _ = T::deserialize(FieldNames(&mut fields));
+            fields
+        }
+
+        #[test]
+        fn documents_every_parameter() {
+            let document = document("");
+            let search = &document["paths"]["/"];
+            let parameters = search["get"]["parameters"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["name"].as_str().unwrap())
+                .collect::<Vec<_>>();
+            let query = field_names::<crate::Req>();
+            assert!(query.contains(&"search[name_matches]"));
+            for field in query {
+                assert!(parameters.contains(field), "{field} isn't documented");
+            }
+            let body = &search["post"]["requestBody"]["content"]["application/json"]["schema"];
+            let json = field_names::<crate::JsonReq>();
+            assert!(json.contains(&"prefix"));
+            for field in json {
+                assert!(
+                    body["properties"].get(field).is_some(),
+                    "{field} isn't documented"
+                );
+            }
+        }
+
+        #[test]
+        fn servers_reflect_the_base_path() {
+            assert_eq!(document("")["servers"], json!([{ "url": "/" }]));
+            assert_eq!(
+                document("/api/ac")["servers"],
+                json!([{ "url": "/api/ac" }])
+            );
+        }
+    }
 }
 
-impl error::ResponseError for AutocompleteError {
-    fn error_response(&self) -> HttpResponse {
-        match *self {
-            AutocompleteError::BadRequest => HttpResponseBuilder::new(self.status_code())
-                .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-                .insert_header((header::CACHE_CONTROL, "private; max-age=0"))
-                .body("{\"error\":\"bad request\"}"),
-            AutocompleteError::ServerError => HttpResponseBuilder::new(self.status_code())
-                .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-                .insert_header((header::CACHE_CONTROL, "private; max-age=0"))
-                .body("{\"error\":\"internal error\"}"),
+mod logging {
+    use std::io::Write;
+
+    use env_logger::fmt::Formatter;
+    use log::kv::{self, Key, Value, VisitSource};
+    use log::Record;
+    use serde::Deserialize;
+    use serde_json::{Map, Value as JsonValue};
+
+    #[derive(Clone, Copy, Default, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LogFormat {
+        /// env_logger's own human-readable format.
+        #[default]
+        Text,
+        /// One JSON object per line, with structured fields at the top level.
+        Json,
+    }
+
+    struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+    impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            let value = serde_json::to_value(value).unwrap_or(JsonValue::Null);
+            self.0.insert(key.to_string(), value);
+            Ok(())
         }
     }
 
-    fn status_code(&self) -> StatusCode {
-        match *self {
-            AutocompleteError::BadRequest => StatusCode::BAD_REQUEST,
-            AutocompleteError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+    fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+        writeln!(buf, "{}", json_line(&buf.timestamp().to_string(), record))
+    }
+
+    /// `record` as the single JSON object [`LogFormat::Json`] writes for it.
+    pub fn json_line(ts: &str, record: &Record) -> JsonValue {
+        let mut line = Map::new();
+        line.insert("ts".into(), ts.into());
+        line.insert("level".into(), record.level().as_str().into());
+        line.insert("target".into(), record.target().into());
+        line.insert("message".into(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut JsonFields(&mut line));
+        JsonValue::Object(line)
+    }
+
+    /// Logs `message` at info level with `fields`, for records whose fields
+    /// are put together by a function rather than listed in a macro call.
+    pub fn info(target: &str, message: &str, fields: &dyn kv::Source) {
+        if log::log_enabled!(target: target, log::Level::Info) {
+            log::logger().log(
+                &Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(log::Level::Info)
+                    .target(target)
+                    .key_values(fields)
+                    .build(),
+            );
+        }
+    }
+
+    pub fn init(format: LogFormat) {
+        let mut builder = env_logger::Builder::from_default_env();
+        if let LogFormat::Json = format {
+            builder.format(format_json);
         }
+        builder.init();
     }
 }
 
-fn validate_transform_tag(tag: &str) -> Result<String, AutocompleteError> {
-    use unicode_normalization::UnicodeNormalization;
-    if tag.chars().take(101).count() > 100 {
-        return Err(AutocompleteError::BadRequest);
+mod metrics {
+    use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounter, Registry};
+
+    /// Latency buckets in seconds. Cache hits take a millisecond or two and
+    /// misses tens of them, which the default buckets lump together.
+    const LATENCY_BUCKETS: &[f64] = &[
+        0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    ];
+
+    #[derive(Clone)]
+    pub struct Metrics {
+        pub registry: Registry,
+        pub requests: IntCounter,
+        /// Labelled by `cache` (`hit` or `miss`) and `status`.
+        pub request_duration: HistogramVec,
+        pub cache_hits: IntCounter,
+        pub cache_misses: IntCounter,
+        pub cache_evictions: IntCounter,
+        pub requests_shed: IntCounter,
+        pub db_query_duration: Histogram,
     }
-    let tag_str: String = tag
-        .nfc()
-        .collect::<String>()
-        .to_lowercase()
-        .replace(['*', '%', '\0'], "")
-        .chars()
-        .filter(|x| !x.is_whitespace())
-        .collect();
-    let len = tag_str.chars().count();
-    if !(3..=100).contains(&len) {
-        return Err(AutocompleteError::BadRequest);
+
+    impl Metrics {
+        pub fn new() -> Result<Self, prometheus::Error> {
+            let registry = Registry::new();
+            let requests = IntCounter::new(
+                "autocomplete_requests_total",
+                "Number of autocomplete requests received",
+            )?;
+            let request_duration = HistogramVec::new(
+                HistogramOpts::new(
+                    "autocomplete_request_duration_seconds",
+                    "Time spent handling autocomplete requests",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["cache", "status"],
+            )?;
+            let cache_hits = IntCounter::new(
+                "autocomplete_cache_hits_total",
+                "Number of autocomplete requests served from the cache",
+            )?;
+            let cache_misses = IntCounter::new(
+                "autocomplete_cache_misses_total",
+                "Number of autocomplete requests that missed the cache",
+            )?;
+            let cache_evictions = IntCounter::new(
+                "autocomplete_cache_evictions_total",
+                "Number of cache entries evicted for expiring or for lack of space",
+            )?;
+            let requests_shed = IntCounter::new(
+                "autocomplete_requests_shed_total",
+                "Number of requests turned away with too many already in flight",
+            )?;
+            let db_query_duration = Histogram::with_opts(
+                HistogramOpts::new(
+                    "autocomplete_db_query_duration_seconds",
+                    "Time spent querying the database for tags",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            )?;
+            registry.register(Box::new(requests.clone()))?;
+            registry.register(Box::new(request_duration.clone()))?;
+            registry.register(Box::new(cache_hits.clone()))?;
+            registry.register(Box::new(cache_misses.clone()))?;
+            registry.register(Box::new(cache_evictions.clone()))?;
+            registry.register(Box::new(requests_shed.clone()))?;
+            registry.register(Box::new(db_query_duration.clone()))?;
+            Ok(Metrics {
+                registry,
+                requests,
+                request_duration,
+                cache_hits,
+                cache_misses,
+                cache_evictions,
+                requests_shed,
+                db_query_duration,
+            })
+        }
     }
-    Ok(tag_str)
 }
 
-#[derive(Deserialize)]
-struct Req {
-    #[serde(rename(deserialize = "search[name_matches]"))]
-    tag_prefix: String,
-}
+mod ratelimit {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
 
-#[get("/")]
-async fn autocomplete(
-    data: web::Data<AutocompleteState>,
-    req: web::Query<Req>,
-) -> Result<HttpResponse, AutocompleteError> {
-    let prefix: String = validate_transform_tag(req.tag_prefix.as_str())?;
-    let cached = data.cache.get(&prefix).await;
-    if let Some(cached_json) = cached {
-        Ok(HttpResponse::Ok()
-            .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-            .insert_header((header::CACHE_CONTROL, "public, max-age=604800"))
-            .body(cached_json))
-    } else {
-        let client = match data.pool.get().await {
-            Ok(x) => x,
-            Err(x) => {
-                error!("{}", x);
-                return Err(AutocompleteError::ServerError);
+    /// Past this many tracked clients, full buckets are dropped from the map.
+    const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+    struct Bucket {
+        tokens: f64,
+        updated: Instant,
+    }
+
+    /// Per-IP token buckets refilled at `rate` tokens per second, up to `burst`.
+    pub struct RateLimiter {
+        rate: f64,
+        burst: f64,
+        buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    }
+
+    impl RateLimiter {
+        pub fn new(rate: f64, burst: u32) -> Self {
+            RateLimiter {
+                rate,
+                burst: f64::from(burst.max(1)),
+                buckets: Mutex::new(HashMap::new()),
             }
-        };
-        let results = match db::get_tags(&client, &prefix).await {
-            Ok(x) => x,
-            Err(x) => {
-                error!("{}", x);
-                return Err(AutocompleteError::ServerError);
+        }
+
+        /// Takes a token for `ip`, or returns how long until one is available.
+        pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap_or_else(|x| x.into_inner());
+            if buckets.len() >= MAX_TRACKED_CLIENTS {
+                // a full bucket carries no state, so it's safe to forget
+                buckets.retain(|_, x| {
+                    x.tokens + now.duration_since(x.updated).as_secs_f64() * self.rate < self.burst
+                });
             }
-        };
-        let serialized = serde_json::to_string(&results).unwrap_or_else(|_| "[]".to_string());
-        let serialized_copy = serialized.clone();
-        data.cache.insert(prefix, serialized).await;
-        Ok(HttpResponse::Ok()
-            .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
-            .insert_header((header::CACHE_CONTROL, "public, max-age=604800"))
-            .body(serialized_copy))
+            let bucket = buckets.entry(ip).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            });
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.updated).as_secs_f64() * self.rate)
+                .min(self.burst);
+            bucket.updated = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn throttles_past_the_burst() {
+            let limiter = RateLimiter::new(1.0, 5);
+            let ip: IpAddr = "203.0.113.9".parse().unwrap();
+            for _ in 0..5 {
+                assert!(limiter.check(ip).is_ok());
+            }
+            let wait = limiter.check(ip).unwrap_err();
+            assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+            // still throttled, with nothing taken for the refused requests
+            for _ in 0..100 {
+                assert!(limiter.check(ip).is_err());
+            }
+            // other clients have their own buckets
+            assert!(limiter.check("203.0.113.10".parse().unwrap()).is_ok());
+        }
+
+        #[test]
+        fn refills_at_the_rate() {
+            let limiter = RateLimiter::new(1000.0, 1);
+            let ip: IpAddr = "2001:db8::1".parse().unwrap();
+            assert!(limiter.check(ip).is_ok());
+            let wait = limiter.check(ip).unwrap_err();
+            std::thread::sleep(wait + Duration::from_millis(5));
+            assert!(limiter.check(ip).is_ok());
+        }
+
+        #[test]
+        fn forgets_full_buckets_when_tracking_too_many() {
+            let limiter = RateLimiter::new(1e9, 2);
+            for x in 0..MAX_TRACKED_CLIENTS as u32 {
+                limiter.check(IpAddr::from(x.to_be_bytes())).unwrap();
+            }
+            limiter.check("203.0.113.9".parse().unwrap()).unwrap();
+            assert!(limiter.buckets.lock().unwrap().len() < MAX_TRACKED_CLIENTS);
+        }
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    use actix_web::{App, HttpServer};
-    use moka::future::CacheBuilder;
-    use std::time::Duration;
-    use tokio_postgres::NoTls;
-    env_logger::init();
+mod tls {
+    use std::sync::Arc;
 
-    let mut config =
-        crate::config::Config::from_env().expect("Failed to load configuration from environment");
-    config.pg.options = Some(match config.pg.options.as_deref() {
-        Some(existing) => format!("{existing} -c statement_timeout=3000"),
-        None => "-c statement_timeout=3000".to_owned(),
-    });
-    let pool = config
-        .pg
-        .create_pool(Some(Runtime::Tokio1), NoTls)
-        .expect("Failed to create PostgreSQL connection pool");
-    let cache = CacheBuilder::new(15_000)
-        .time_to_live(Duration::from_secs(6 * 60 * 60))
-        .build();
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                DefaultHeaders::new()
-                    .add((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"))
-                    .add((header::ACCESS_CONTROL_ALLOW_HEADERS, "Authorization")),
-            )
-            .app_data(Data::new(AutocompleteState {
-                pool: pool.clone(),
-                cache: cache.clone(),
-            }))
-            .service(autocomplete)
-    })
-    .bind(config.server_addr.clone())?
-    .run()
-    .await
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use tokio_postgres_rustls::MakeRustlsConnect;
+
+    /// A connector for TLS connections to PostgreSQL, trusting the PEM
+    /// certificates in `ca_path` if given and the public web roots otherwise.
+    pub fn postgres_connector(ca_path: Option<&str>) -> Result<MakeRustlsConnect, String> {
+        let mut roots = RootCertStore::empty();
+        match ca_path {
+            Some(path) => {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .map_err(|x| format!("failed to read {path}: {x}"))?;
+                for cert in certs {
+                    let cert = cert.map_err(|x| format!("failed to parse {path}: {x}"))?;
+                    roots
+                        .add(cert)
+                        .map_err(|x| format!("invalid certificate in {path}: {x}"))?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("no certificates found in {path}"));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(|x| x.to_string())?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        Ok(MakeRustlsConnect::new(config))
+    }
+
+    /// The HTTPS listener's config, from a certificate chain and private key
+    /// that may each be PEM or DER.
+    pub fn server_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, String> {
+        let certs = read(cert_path)?;
+        let certs = if is_pem(&certs) {
+            CertificateDer::pem_slice_iter(&certs)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|x| format!("failed to parse {cert_path}: {x}"))?
+        } else {
+            vec![CertificateDer::from(certs)]
+        };
+        if certs.is_empty() {
+            return Err(format!("no certificates found in {cert_path}"));
+        }
+        let key = read(key_path)?;
+        let key = if is_pem(&key) {
+            PrivateKeyDer::from_pem_slice(&key).map_err(|x| x.to_string())
+        } else {
+            PrivateKeyDer::try_from(key).map_err(str::to_owned)
+        }
+        .map_err(|x| format!("failed to parse {key_path}: {x}"))?;
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|x| x.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|x| format!("invalid certificate or key: {x}"))
+    }
+
+    fn read(path: &str) -> Result<Vec<u8>, String> {
+        std::fs::read(path).map_err(|x| format!("failed to read {path}: {x}"))
+    }
+
+    fn is_pem(data: &[u8]) -> bool {
+        data.trim_ascii_start().starts_with(b"-----BEGIN")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// A self-signed certificate for `localhost` and its key.
+        const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.crt.pem");
+        const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.key.pem");
+
+        /// Writes `data` to a fresh file in the temporary directory.
+        fn temp_file(data: &[u8]) -> String {
+            let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            std::fs::write(&path, data).unwrap();
+            path.to_str().unwrap().to_owned()
+        }
+
+        #[test]
+        fn postgres_connector_trusts_the_given_roots() {
+            assert!(postgres_connector(None).is_ok());
+            assert!(postgres_connector(Some(CERT)).is_ok());
+            let err = |path| postgres_connector(Some(path)).err().unwrap();
+            assert!(err("/nonexistent.pem").starts_with("failed to read /nonexistent.pem"));
+            assert_eq!(err(KEY), format!("no certificates found in {KEY}"));
+        }
+
+        #[test]
+        fn server_config_loads_pem_and_der() {
+            assert!(server_config(CERT, KEY).is_ok());
+            let cert = temp_file(&CertificateDer::from_pem_file(CERT).unwrap());
+            let key = temp_file(PrivateKeyDer::from_pem_file(KEY).unwrap().secret_der());
+            assert!(server_config(&cert, &key).is_ok());
+            assert!(server_config(CERT, &key).is_ok());
+            std::fs::remove_file(cert).unwrap();
+            std::fs::remove_file(key).unwrap();
+        }
+
+        #[test]
+        fn server_config_says_what_is_wrong() {
+            let err = |cert, key| server_config(cert, key).err().unwrap();
+            assert!(err("/nonexistent.pem", KEY).starts_with("failed to read /nonexistent.pem"));
+            assert_eq!(err(KEY, KEY), format!("no certificates found in {KEY}"));
+            assert!(err(CERT, CERT).starts_with(&format!("failed to parse {CERT}")));
+            let garbage = temp_file(b"not a key");
+            assert!(err(CERT, &garbage).starts_with(&format!("failed to parse {garbage}")));
+            std::fs::remove_file(garbage).unwrap();
+        }
+    }
+}
+
+mod db {
+    use deadpool_postgres::{Client, GenericClient};
+    use derive_more::{Display, From};
+    use tokio_pg_mapper::FromTokioPostgresRow;
+
+    use tokio_postgres::error::SqlState;
+    use tokio_postgres::types::ToSql;
+
+    use std::time::{Duration, Instant};
+
+    use crate::models::{Order, Tag};
+    use crate::TagSearch;
+
+    const FETCH_TAG: &str = include_str!("../sql/fetch_tag.sql");
+    const FETCH_TOP_TAGS: &str = include_str!("../sql/fetch_top_tags.sql");
+    const FETCH_TAGS_A: &str = include_str!("../sql/fetch_tags_a.sql");
+    const COUNT_TAGS_A: &str = include_str!("../sql/count_tags_a.sql");
+    const FETCH_TAGS_B: &str = include_str!("../sql/fetch_tags_b.sql");
+    const COUNT_TAGS_B: &str = include_str!("../sql/count_tags_b.sql");
+    const FETCH_TAGS_C: &str = include_str!("../sql/fetch_tags_c.sql");
+    const COUNT_TAGS_C: &str = include_str!("../sql/count_tags_c.sql");
+    /// Reads precomputed results from the table named in place of `{table}`.
+    const FETCH_PREFIX_TABLE: &str = include_str!("../sql/fetch_prefix_table.sql");
+
+    /// The stage A and B queries, which operators can replace with their own
+    /// taking the same parameters and returning the same columns, and the
+    /// lookup in the precomputed prefix table if there is one.
+    pub struct Queries {
+        fetch_a: String,
+        fetch_b: String,
+        fetch_prefix_table: Option<String>,
+    }
+
+    impl Queries {
+        /// Reads the queries from the given files, using the embedded ones
+        /// for any that aren't given.
+        pub fn load(
+            fetch_a_path: Option<&str>,
+            fetch_b_path: Option<&str>,
+            prefix_table: Option<&str>,
+        ) -> Result<Self, String> {
+            let read = |path: Option<&str>, embedded: &str| match path {
+                Some(path) => {
+                    std::fs::read_to_string(path).map_err(|x| format!("couldn't read {path}: {x}"))
+                }
+                None => Ok(embedded.to_owned()),
+            };
+            // spliced into the query, so it can only be a plain (schema-qualified) name
+            let is_identifier = |x: &str| {
+                x.split('.').all(|part| {
+                    part.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+                        && part.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+                })
+            };
+            let fetch_prefix_table = match prefix_table {
+                Some(table) if !is_identifier(table) => {
+                    return Err(format!("{table} isn't a valid table name"));
+                }
+                Some(table) => Some(FETCH_PREFIX_TABLE.replace("{table}", table)),
+                None => None,
+            };
+            Ok(Queries {
+                fetch_a: read(fetch_a_path, FETCH_TAGS_A)?,
+                fetch_b: read(fetch_b_path, FETCH_TAGS_B)?,
+                fetch_prefix_table,
+            })
+        }
+
+        /// Every query by file name, for [`check_schema`].
+        fn all(&self) -> Vec<(&str, &str)> {
+            let mut all = vec![
+                ("fetch_tag.sql", FETCH_TAG),
+                ("fetch_top_tags.sql", FETCH_TOP_TAGS),
+                ("fetch_tags_a.sql", &self.fetch_a),
+                ("count_tags_a.sql", COUNT_TAGS_A),
+                ("fetch_tags_b.sql", &self.fetch_b),
+                ("count_tags_b.sql", COUNT_TAGS_B),
+                ("fetch_tags_c.sql", FETCH_TAGS_C),
+                ("count_tags_c.sql", COUNT_TAGS_C),
+            ];
+            if let Some(query) = &self.fetch_prefix_table {
+                all.push(("fetch_prefix_table.sql", query));
+            }
+            all
+        }
+    }
+
+    /// The columns the queries read and [`Tag`] is mapped from.
+    const REQUIRED_COLUMNS: &[(&str, &str)] = &[
+        ("tags", "id"),
+        ("tags", "name"),
+        ("tags", "post_count"),
+        ("tags", "category"),
+        ("tag_aliases", "antecedent_name"),
+        ("tag_aliases", "consequent_name"),
+        ("tag_aliases", "status"),
+        ("tag_aliases", "post_count"),
+    ];
+
+    /// Turns a prefix into a `LIKE ... ESCAPE '\'` pattern: `*` is a wildcard,
+    /// `\*` a literal asterisk, and everything else, backslashes included, is
+    /// matched literally.
+    fn escape_like(stuff: &str) -> String {
+        let mut pattern = String::with_capacity(stuff.len() + 4);
+        let mut chars = stuff.chars().peekable();
+        while let Some(x) = chars.next() {
+            match x {
+                '\\' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push('*');
+                }
+                '\\' | '%' | '_' => {
+                    pattern.push('\\');
+                    pattern.push(x);
+                }
+                '*' => pattern.push('%'),
+                x => pattern.push(x),
+            }
+        }
+        pattern
+    }
+
+    /// The query stage that produced a result set.
+    #[derive(Clone, Copy)]
+    pub enum Stage {
+        A,
+        B,
+        C,
+    }
+
+    impl Stage {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                Stage::A => "a",
+                Stage::B => "b",
+                Stage::C => "c",
+            }
+        }
+    }
+
+    pub struct Matches {
+        pub tags: Vec<Tag>,
+        /// Total number of matches across all pages, for paginated searches.
+        pub total: Option<i64>,
+        /// The stage that matched, if any did.
+        pub stage: Option<Stage>,
+        /// How many of `tags`, from the start, stage A matched. They're the
+        /// only ones ordered by post count alone, then name.
+        pub from_stage_a: usize,
+        /// How long each stage that ran took, in order.
+        pub timings: Vec<(Stage, Duration)>,
+    }
+
+    #[derive(Debug, Display, From)]
+    pub enum DbError {
+        #[display(fmt = "{}", _0)]
+        Query(tokio_postgres::Error),
+        #[display(fmt = "{}", _0)]
+        Mapping(tokio_pg_mapper::Error),
+    }
+
+    impl DbError {
+        /// A coarse classification for logs, since the messages alone don't
+        /// make a timeout easy to tell from a dropped connection.
+        pub fn kind(&self) -> &'static str {
+            match self {
+                DbError::Query(x) if x.code() == Some(&SqlState::QUERY_CANCELED) => {
+                    "statement_timeout"
+                }
+                // the server's parting words when it closes a connection under a
+                // query, as replicas recycling theirs do
+                DbError::Query(x)
+                    if x.is_closed()
+                        || [
+                            SqlState::ADMIN_SHUTDOWN,
+                            SqlState::CRASH_SHUTDOWN,
+                            SqlState::IDLE_SESSION_TIMEOUT,
+                        ]
+                        .iter()
+                        .any(|code| x.code() == Some(code)) =>
+                {
+                    "connection_closed"
+                }
+                DbError::Query(x) if x.code().is_some() => "sql",
+                DbError::Query(_) => "connection",
+                DbError::Mapping(_) => "mapping",
+            }
+        }
+
+        /// The SQLSTATE code the server reported, if it got that far.
+        pub fn sql_state(&self) -> Option<&str> {
+            match self {
+                DbError::Query(x) => x.code().map(SqlState::code),
+                DbError::Mapping(_) => None,
+            }
+        }
+
+        /// Whether the query was cancelled by `statement_timeout`.
+        pub fn is_timeout(&self) -> bool {
+            self.kind() == "statement_timeout"
+        }
+
+        /// Whether the connection was found closed, which says nothing about
+        /// the query and is worth retrying on another.
+        pub fn is_closed(&self) -> bool {
+            self.kind() == "connection_closed"
+        }
+    }
+
+    async fn query_tags(
+        client: &impl GenericClient,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Tag>, DbError> {
+        let stmt = client.prepare_cached(query).await?;
+        let rows = client
+            .query(&stmt, params)
+            .await?
+            .iter()
+            .map(Tag::from_row_ref)
+            .collect::<Result<Vec<Tag>, _>>()?;
+        Ok(rows)
+    }
+
+    async fn count_tags(
+        client: &impl GenericClient,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<i64, DbError> {
+        let stmt = client.prepare_cached(query).await?;
+        Ok(client.query_one(&stmt, params).await?.get("total"))
+    }
+
+    pub async fn get_tag(client: &Client, name: &str) -> Result<Option<Tag>, DbError> {
+        let stmt = client.prepare_cached(FETCH_TAG).await?;
+        match client.query_opt(&stmt, &[&name]).await? {
+            Some(row) => Ok(Some(Tag::from_row_ref(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that the tables have the columns we need and that every query
+    /// prepares, so schema drift fails startup rather than the first requests.
+    /// Nothing is executed.
+    pub async fn check_schema(client: &Client, queries: &Queries) -> Result<(), String> {
+        // the tables the queries resolve to, so one of the same name further
+        // along the search path can't stand in for a missing column
+        let rows = client
+            .query(
+                "SELECT attrelid::regclass::text, attname::text FROM pg_attribute
+                 WHERE attrelid IN (to_regclass('tags'), to_regclass('tag_aliases'))
+                   AND attnum > 0 AND NOT attisdropped",
+                &[],
+            )
+            .await
+            .map_err(|x| describe(&x))?;
+        let present: Vec<(String, String)> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        let missing: Vec<String> = REQUIRED_COLUMNS
+            .iter()
+            .filter(|(table, column)| !present.iter().any(|(t, c)| t == table && c == column))
+            .map(|(table, column)| format!("{table}.{column}"))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("missing columns: {}", missing.join(", ")));
+        }
+        for (file, query) in queries.all() {
+            client
+                .prepare(query)
+                .await
+                .map_err(|x| format!("{file} doesn't match the schema: {}", describe(&x)))?;
+        }
+        Ok(())
+    }
+
+    /// The server's message for an error, which the error's own `Display`
+    /// leaves out.
+    fn describe(x: &tokio_postgres::Error) -> String {
+        match x.as_db_error() {
+            Some(db) => db.message().to_owned(),
+            None => x.to_string(),
+        }
+    }
+
+    /// [`get_tags`] under a `statement_timeout` of `ms`. The override is local
+    /// to a transaction, so it ends with the search even if that's abandoned
+    /// halfway: dropping the transaction rolls it back.
+    pub async fn get_tags_with_timeout(
+        client: &mut Client,
+        ms: u64,
+        search: &TagSearch,
+        strategy: &Strategy,
+        queries: &Queries,
+    ) -> Result<Matches, DbError> {
+        let transaction = client.transaction().await?;
+        // numeric, so formatting it in is safe; SET doesn't take parameters
+        transaction
+            .batch_execute(&format!("SET LOCAL statement_timeout = {ms}"))
+            .await?;
+        let matches = get_tags(&transaction, search, strategy, queries).await?;
+        transaction.commit().await?;
+        Ok(matches)
+    }
+
+    pub async fn get_top_tag_names(client: &Client, limit: i64) -> Result<Vec<String>, DbError> {
+        let stmt = client.prepare_cached(FETCH_TOP_TAGS).await?;
+        let rows = client.query(&stmt, &[&limit]).await?;
+        Ok(rows.iter().map(|row| row.get("name")).collect())
+    }
+
+    /// Which stages run, how they rank and what they may return, from the
+    /// configuration.
+    #[derive(Clone)]
+    pub struct Strategy {
+        pub trigram_fallback: bool,
+        pub weighted_ranking: bool,
+        /// Tops up a short result set from the later stages rather than only
+        /// falling through when a stage found nothing.
+        pub fill_from_fallback: bool,
+        /// Categories that may be searched at all, or `None` for every one.
+        pub allowed_categories: Option<Vec<i16>>,
+        /// Prefixes up to this many characters are looked up in the prefix
+        /// table first, when there is one.
+        pub prefix_table_max_len: usize,
+    }
+
+    impl Strategy {
+        pub fn allows(&self, category: i16) -> bool {
+            self.allowed_categories
+                .as_ref()
+                .is_none_or(|x| x.contains(&category))
+        }
+    }
+
+    /// Runs each stage in turn and returns the first one's results that
+    /// matched anything, along with the total match count when paginating.
+    /// With `fill_from_fallback`, unpaginated results are topped up from the
+    /// later stages until there are `limit` of them.
+    pub async fn get_tags(
+        client: &impl GenericClient,
+        search: &TagSearch,
+        strategy: &Strategy,
+        queries: &Queries,
+    ) -> Result<Matches, DbError> {
+        // the table only holds the first page by popularity for literal
+        // prefixes; anything else, or a prefix it has nothing for, goes live
+        let precomputed = queries.fetch_prefix_table.as_deref().filter(|_| {
+            search.offset.is_none()
+                && search.order == Order::Count
+                && !search.prefix.contains('*')
+                && search.prefix.chars().count() <= strategy.prefix_table_max_len
+        });
+        if let Some(fetch) = precomputed {
+            let started = Instant::now();
+            let tags = query_tags(
+                client,
+                fetch,
+                &[
+                    &search.prefix,
+                    &search.limit,
+                    &search.category,
+                    &search.min_post_count,
+                    &strategy.allowed_categories,
+                ],
+            )
+            .await?;
+            if !tags.is_empty() {
+                return Ok(Matches {
+                    from_stage_a: tags.len(),
+                    tags,
+                    total: None,
+                    stage: Some(Stage::A),
+                    timings: vec![(Stage::A, started.elapsed())],
+                });
+            }
+        }
+        // appended after escaping, so a trailing backslash can't escape it
+        let escape_prefix = escape_like(&search.prefix) + "%";
+        let order = search.order.as_str();
+        let offset = search.offset.unwrap_or(0);
+        let params_a: &[&(dyn ToSql + Sync)] = &[
+            &escape_prefix,
+            &search.limit,
+            &search.category,
+            &order,
+            &search.min_post_count,
+            &offset,
+            &strategy.allowed_categories,
+        ];
+        // the fuzzy stages can rank names starting with the prefix ahead of
+        // merely similar ones, blended with popularity
+        let params_b: &[&(dyn ToSql + Sync)] = &[
+            &search.prefix,
+            &search.limit,
+            &search.category,
+            &order,
+            &search.min_post_count,
+            &offset,
+            &strategy.weighted_ranking,
+            &escape_prefix,
+            &strategy.allowed_categories,
+        ];
+        let min_similarity = f32::from(search.min_similarity) / 1000.0;
+        let params_c: &[&(dyn ToSql + Sync)] = &[
+            &search.prefix,
+            &search.limit,
+            &search.category,
+            &order,
+            &search.min_post_count,
+            &offset,
+            &strategy.weighted_ranking,
+            &escape_prefix,
+            &strategy.allowed_categories,
+            &min_similarity,
+        ];
+        let count_params = |pattern| -> Vec<&(dyn ToSql + Sync)> {
+            vec![
+                pattern,
+                &search.category,
+                &search.min_post_count,
+                &strategy.allowed_categories,
+            ]
+        };
+        let mut stages = vec![
+            (
+                Stage::A,
+                queries.fetch_a.as_str(),
+                params_a,
+                COUNT_TAGS_A,
+                count_params(&escape_prefix),
+            ),
+            (
+                Stage::B,
+                queries.fetch_b.as_str(),
+                params_b,
+                COUNT_TAGS_B,
+                count_params(&search.prefix),
+            ),
+        ];
+        // word similarity catches typos in a prefix of a longer name, which
+        // whole-name similarity in stage B scores too low to match
+        if strategy.trigram_fallback {
+            let mut count_params_c = count_params(&search.prefix);
+            count_params_c.push(&min_similarity);
+            stages.push((
+                Stage::C,
+                FETCH_TAGS_C,
+                params_c,
+                COUNT_TAGS_C,
+                count_params_c,
+            ));
+        }
+        if search.prefix_only {
+            stages.truncate(1);
+        }
+        let limit = usize::try_from(search.limit).unwrap_or_default();
+        let mut tags: Vec<Tag> = Vec::new();
+        let mut from_stage_a = 0;
+        let mut matched = None;
+        let mut timings = Vec::new();
+        for (stage, fetch, params, count, count_params) in stages {
+            let started = Instant::now();
+            if search.offset.is_some() {
+                // a page past the end is empty without the stage being a miss,
+                // so fall through on the count rather than on the page
+                let total = count_tags(client, count, &count_params).await?;
+                let found = match total {
+                    0 => None,
+                    _ => Some(query_tags(client, fetch, params).await?),
+                };
+                timings.push((stage, started.elapsed()));
+                if let Some(tags) = found {
+                    return Ok(Matches {
+                        from_stage_a: if matches!(stage, Stage::A) {
+                            tags.len()
+                        } else {
+                            0
+                        },
+                        tags,
+                        total: Some(total),
+                        stage: Some(stage),
+                        timings,
+                    });
+                }
+            } else {
+                let found = query_tags(client, fetch, params).await?;
+                timings.push((stage, started.elapsed()));
+                if found.is_empty() {
+                    continue;
+                }
+                // earlier stages' matches stay ahead of later ones
+                for tag in found {
+                    if !tags.iter().any(|x| x.id == tag.id) {
+                        tags.push(tag);
+                    }
+                }
+                if matches!(stage, Stage::A) {
+                    from_stage_a = tags.len();
+                }
+                matched.get_or_insert(stage);
+                if !strategy.fill_from_fallback || tags.len() >= limit {
+                    break;
+                }
+            }
+        }
+        tags.truncate(limit);
+        Ok(Matches {
+            from_stage_a: from_stage_a.min(tags.len()),
+            tags,
+            total: search.offset.map(|_| 0),
+            stage: matched,
+            timings,
+        })
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+        use crate::models::{Format, Order};
+        use deadpool_postgres::{Pool, Runtime};
+        use tokio_postgres::NoTls;
+
+        /// A throwaway schema with the service's tables, in the database named
+        /// by `PG_TEST_URL` (a libpq connection string or URL). `pg_trgm` has
+        /// to be installed there already. Tests that need one skip when the
+        /// variable isn't set.
+        pub struct TestDb {
+            pub pool: Pool,
+            schema: String,
+        }
+
+        impl TestDb {
+            pub async fn new() -> Option<Self> {
+                let Ok(url) = std::env::var("PG_TEST_URL") else {
+                    eprintln!("PG_TEST_URL isn't set, skipping");
+                    return None;
+                };
+                let schema = format!("autocompleted_test_{}", uuid::Uuid::new_v4().simple());
+                let pg = deadpool_postgres::Config {
+                    url: Some(url),
+                    // pg_trgm's operators live in public
+                    options: Some(format!("-c search_path={schema},public")),
+                    ..Default::default()
+                };
+                let pool = pg
+                    .create_pool(Some(Runtime::Tokio1), NoTls)
+                    .expect("Failed to create test pool");
+                let client = pool.get().await.expect("Failed to connect to PG_TEST_URL");
+                client
+                    .batch_execute(&format!(
+                        "CREATE SCHEMA {schema};
+                        CREATE TABLE {schema}.tags (
+                            id serial PRIMARY KEY,
+                            name text NOT NULL UNIQUE,
+                            post_count integer NOT NULL DEFAULT 0,
+                            category smallint NOT NULL DEFAULT 0
+                        );
+                        CREATE TABLE {schema}.tag_aliases (
+                            id serial PRIMARY KEY,
+                            antecedent_name text NOT NULL,
+                            consequent_name text NOT NULL,
+                            status text NOT NULL DEFAULT 'active',
+                            post_count integer NOT NULL DEFAULT 0
+                        );"
+                    ))
+                    .await
+                    .expect("Failed to create test schema");
+                Some(TestDb { pool, schema })
+            }
+
+            /// Adds tags as `(name, post_count, category)`.
+            pub async fn tags(&self, tags: &[(&str, i32, i16)]) {
+                let client = self.pool.get().await.unwrap();
+                for (name, post_count, category) in tags {
+                    client
+                        .execute(
+                            "INSERT INTO tags (name, post_count, category) VALUES ($1, $2, $3)",
+                            &[name, post_count, category],
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+
+            /// Adds active aliases as `(antecedent, consequent)`.
+            pub async fn aliases(&self, aliases: &[(&str, &str)]) {
+                let client = self.pool.get().await.unwrap();
+                for (antecedent, consequent) in aliases {
+                    client
+                        .execute(
+                            "INSERT INTO tag_aliases (antecedent_name, consequent_name, post_count) \
+                             VALUES ($1, $2, 1)",
+                            &[antecedent, consequent],
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+
+            pub async fn get_tags(&self, search: &TagSearch, strategy: &Strategy) -> Matches {
+                let client = self.pool.get().await.unwrap();
+                let queries = Queries::load(None, None, None).unwrap();
+                get_tags(&client, search, strategy, &queries).await.unwrap()
+            }
+
+            /// Drops the schema. A test that fails before getting here leaves
+            /// it behind, under a name no other run will use.
+            pub async fn finish(self) {
+                let client = self.pool.get().await.unwrap();
+                client
+                    .batch_execute(&format!("DROP SCHEMA {} CASCADE", self.schema))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        pub fn search(prefix: &str) -> TagSearch {
+            TagSearch {
+                prefix: prefix.to_owned(),
+                limit: 10,
+                category: None,
+                order: Order::Count,
+                min_post_count: 1,
+                min_similarity: 0,
+                offset: None,
+                resolve_aliases: false,
+                category_names: false,
+                annotate_exact: false,
+                group_by_category: false,
+                prefix_only: false,
+                language: None,
+                format: Format::Json,
+                fields: None,
+                cache_version: "".into(),
+            }
+        }
+
+        pub fn strategy() -> Strategy {
+            Strategy {
+                trigram_fallback: false,
+                weighted_ranking: false,
+                fill_from_fallback: false,
+                allowed_categories: None,
+                prefix_table_max_len: 3,
+            }
+        }
+
+        #[test]
+        fn escape_like_escapes_in_one_pass() {
+            assert_eq!(escape_like("fur"), "fur");
+            assert_eq!(escape_like("fur\\"), "fur\\\\");
+            assert_eq!(escape_like("\\"), "\\\\");
+            assert_eq!(escape_like("st\\*ar"), "st*ar");
+            assert_eq!(escape_like("st*ar"), "st%ar");
+            assert_eq!(escape_like("100%"), "100\\%");
+            assert_eq!(escape_like("long_hair"), "long\\_hair");
+            // only `\*` is an escape, so the backslash before one is literal
+            assert_eq!(escape_like("a\\\\*"), "a\\\\*");
+            assert_eq!(escape_like("\\*%_*"), "*\\%\\_%");
+        }
+
+        #[test]
+        fn queries_are_embedded_unless_overridden() {
+            let embedded = Queries::load(None, None, None).unwrap();
+            assert_eq!(embedded.fetch_a, FETCH_TAGS_A);
+            assert_eq!(embedded.fetch_b, FETCH_TAGS_B);
+            assert!(embedded.fetch_prefix_table.is_none());
+            let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            std::fs::create_dir(&dir).unwrap();
+            let path = dir.join("fetch_tags_b.sql");
+            std::fs::write(&path, "SELECT 1").unwrap();
+            let path = path.to_str().unwrap();
+            let overridden = Queries::load(None, Some(path), None).unwrap();
+            assert_eq!(overridden.fetch_a, FETCH_TAGS_A);
+            assert_eq!(overridden.fetch_b, "SELECT 1");
+            let missing = dir.join("fetch_tags_a.sql");
+            let missing = missing.to_str().unwrap();
+            let err = Queries::load(Some(missing), Some(path), None)
+                .err()
+                .unwrap();
+            assert!(
+                err.starts_with(&format!("couldn't read {missing}")),
+                "{err}"
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        fn names(matches: &Matches) -> Vec<&str> {
+            matches.tags.iter().map(|x| x.name.as_str()).collect()
+        }
+
+        async fn seeded() -> Option<TestDb> {
+            let db = TestDb::new().await?;
+            db.tags(&[
+                ("fur", 100, 0),
+                ("furry", 5000, 0),
+                ("furniture", 300, 0),
+                ("fox", 2000, 5),
+                ("wolf", 1500, 5),
+            ])
+            .await;
+            db.aliases(&[("vulpine", "fox")]).await;
+            Some(db)
+        }
+
+        #[actix_web::test]
+        async fn prefix_matches_most_used_first() {
+            let Some(db) = seeded().await else { return };
+            let matches = db.get_tags(&search("fur"), &strategy()).await;
+            assert_eq!(names(&matches), ["furry", "furniture", "fur"]);
+            assert!(matches!(matches.stage, Some(Stage::A)));
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn prefix_matches_aliases() {
+            let Some(db) = seeded().await else { return };
+            let matches = db.get_tags(&search("vulp"), &strategy()).await;
+            assert_eq!(names(&matches), ["fox"]);
+            assert_eq!(matches.tags[0].antecedent_name.as_deref(), Some("vulpine"));
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn typo_falls_back_to_similarity() {
+            let Some(db) = seeded().await else { return };
+            let matches = db.get_tags(&search("furnitre"), &strategy()).await;
+            assert_eq!(names(&matches)[0], "furniture");
+            assert!(matches!(matches.stage, Some(Stage::B)));
+            let prefix_only = TagSearch {
+                prefix_only: true,
+                ..search("furnitre")
+            };
+            let matches = db.get_tags(&prefix_only, &strategy()).await;
+            assert!(matches.tags.is_empty());
+            assert!(matches.stage.is_none());
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn name_order_is_alphabetical_over_every_match() {
+            let Some(db) = seeded().await else { return };
+            db.aliases(&[("furred", "wolf")]).await;
+            let by_name = TagSearch {
+                limit: 2,
+                order: Order::Name,
+                ..search("fur")
+            };
+            let matches = db.get_tags(&by_name, &strategy()).await;
+            assert_eq!(names(&matches), ["fur", "furniture"]);
+            let by_name = TagSearch {
+                limit: 10,
+                ..by_name
+            };
+            let matches = db.get_tags(&by_name, &strategy()).await;
+            assert_eq!(names(&matches), ["fur", "furniture", "furry", "wolf"]);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn name_order_pages_alphabetically() {
+            let Some(db) = seeded().await else { return };
+            let second = TagSearch {
+                limit: 1,
+                offset: Some(1),
+                order: Order::Name,
+                ..search("fur")
+            };
+            let matches = db.get_tags(&second, &strategy()).await;
+            assert_eq!(names(&matches), ["furniture"]);
+            let typo = TagSearch {
+                prefix: "furnitre".to_owned(),
+                ..second
+            };
+            let matches = db.get_tags(&typo, &strategy()).await;
+            assert!(matches!(matches.stage, Some(Stage::B)));
+            assert_eq!(names(&matches), ["furniture"]);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn escaped_characters_match_literally() {
+            let Some(db) = TestDb::new().await else {
+                return;
+            };
+            db.tags(&[
+                ("st*ar", 10, 0),
+                ("star_wars", 20, 0),
+                ("starxwars", 30, 0),
+                ("back\\slash", 10, 0),
+            ])
+            .await;
+            let matches = db.get_tags(&search("st\\*a"), &strategy()).await;
+            assert_eq!(names(&matches), ["st*ar"]);
+            let matches = db.get_tags(&search("st*_w"), &strategy()).await;
+            assert_eq!(names(&matches), ["star_wars"]);
+            let matches = db.get_tags(&search("back\\"), &strategy()).await;
+            assert_eq!(names(&matches), ["back\\slash"]);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn fills_report_where_prefix_matches_end() {
+            let Some(db) = seeded().await else { return };
+            let fill = Strategy {
+                fill_from_fallback: true,
+                ..strategy()
+            };
+            let matches = db.get_tags(&search("furn"), &fill).await;
+            assert_eq!(names(&matches)[0], "furniture");
+            assert!(matches.tags.len() > 1);
+            assert_eq!(matches.from_stage_a, 1);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn timeout_overrides_end_with_the_search() {
+            let Some(db) = seeded().await else { return };
+            async fn session_timeout(client: &Client) -> String {
+                let row = client.query_one("SHOW statement_timeout", &[]).await;
+                row.unwrap().get(0)
+            }
+            let (fur, strategy) = (search("fur"), strategy());
+            let queries = Queries::load(None, None, None).unwrap();
+            let mut client = db.pool.get().await.unwrap();
+            let before = session_timeout(&client).await;
+            let matches = get_tags_with_timeout(&mut client, 1234, &fur, &strategy, &queries)
+                .await
+                .unwrap();
+            assert_eq!(matches.tags.len(), 3);
+            assert_eq!(session_timeout(&client).await, before);
+            // abandoned at whatever point the timer fires
+            for micros in [1, 50, 200, 1000, 5000] {
+                let search = get_tags_with_timeout(&mut client, 1234, &fur, &strategy, &queries);
+                let _ = actix_web::rt::time::timeout(Duration::from_micros(micros), search).await;
+                assert_eq!(session_timeout(&client).await, before, "{micros}");
+            }
+            drop(client);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn pages_report_the_total() {
+            let Some(db) = seeded().await else { return };
+            let page = TagSearch {
+                limit: 1,
+                offset: Some(1),
+                ..search("fur")
+            };
+            let matches = db.get_tags(&page, &strategy()).await;
+            assert_eq!(names(&matches), ["furniture"]);
+            assert_eq!(matches.total, Some(3));
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn filters_by_category() {
+            let Some(db) = seeded().await else { return };
+            db.tags(&[("formal_wear", 50, 0)]).await;
+            let species = TagSearch {
+                category: Some(5),
+                ..search("fo")
+            };
+            let matches = db.get_tags(&species, &strategy()).await;
+            assert_eq!(names(&matches), ["fox"]);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn rows_that_dont_map_are_an_error() {
+            let Some(db) = seeded().await else { return };
+            let client = db.pool.get().await.unwrap();
+            // still comparable with the queries' smallints, but no longer an i16
+            client
+                .batch_execute("ALTER TABLE tags ALTER category TYPE integer")
+                .await
+                .unwrap();
+            let queries = Queries::load(None, None, None).unwrap();
+            let result = get_tags(&client, &search("fur"), &strategy(), &queries).await;
+            let Err(x) = result else {
+                panic!("a row that doesn't map was returned");
+            };
+            assert_eq!(x.kind(), "mapping");
+            assert!(!x.is_closed());
+            // a plain 500 at the handler
+            assert!(matches!(
+                crate::query_error(x),
+                crate::AutocompleteError::ServerError
+            ));
+            drop(client);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn schema_check_names_what_is_missing() {
+            let Some(db) = TestDb::new().await else {
+                return;
+            };
+            let client = db.pool.get().await.unwrap();
+            let queries = Queries::load(None, None, None).unwrap();
+            assert_eq!(check_schema(&client, &queries).await, Ok(()));
+            // an override that refers to a column that doesn't exist
+            let path = std::env::temp_dir().join(format!("{}.sql", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &path,
+                "SELECT id, name, post_count, category, alias FROM tags",
+            )
+            .unwrap();
+            let overridden = Queries::load(path.to_str(), None, None).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let err = check_schema(&client, &overridden).await.unwrap_err();
+            assert!(
+                err.starts_with("fetch_tags_a.sql doesn't match the schema"),
+                "{err}"
+            );
+            client
+                .batch_execute("ALTER TABLE tags RENAME post_count TO posts")
+                .await
+                .unwrap();
+            let err = check_schema(&client, &queries).await.unwrap_err();
+            assert_eq!(err, "missing columns: tags.post_count");
+            drop(client);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn short_prefixes_come_from_the_prefix_table() {
+            let Some(db) = seeded().await else { return };
+            let client = db.pool.get().await.unwrap();
+            // deliberately not what a live search would find
+            client
+                .batch_execute(
+                    "CREATE TABLE prefixes (prefix text, tag_id integer, antecedent_name text);
+                    INSERT INTO prefixes SELECT 'fo', id, null FROM tags WHERE name = 'wolf';
+                    INSERT INTO prefixes SELECT 'furr', id, null FROM tags WHERE name = 'fur';",
+                )
+                .await
+                .unwrap();
+            let queries = Queries::load(None, None, Some("prefixes")).unwrap();
+            let get = |prefix: &str| {
+                let search = search(prefix);
+                let (client, queries) = (&client, &queries);
+                async move {
+                    let matches = get_tags(client, &search, &strategy(), queries).await;
+                    names(&matches.unwrap())
+                        .into_iter()
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(get("fo").await, ["wolf"]);
+            // short, but the table has nothing for it
+            assert_eq!(get("fox").await, ["fox"]);
+            // longer than the table covers
+            assert_eq!(get("furr").await, ["furry"]);
+            drop(client);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn equal_counts_page_in_a_stable_order() {
+            let Some(db) = TestDb::new().await else {
+                return;
+            };
+            // inserted out of order, so ids don't line up with names
+            db.tags(&[("fox_c", 10, 0), ("fox_a", 10, 0), ("fox_b", 10, 0)])
+                .await;
+            let matches = db.get_tags(&search("fox"), &strategy()).await;
+            assert_eq!(names(&matches), ["fox_a", "fox_b", "fox_c"]);
+            for _ in 0..3 {
+                let mut paged = Vec::new();
+                for offset in 0..3 {
+                    let page = TagSearch {
+                        limit: 1,
+                        offset: Some(offset),
+                        ..search("fox")
+                    };
+                    let matches = db.get_tags(&page, &strategy()).await;
+                    paged.extend(names(&matches).into_iter().map(str::to_owned));
+                }
+                assert_eq!(paged, ["fox_a", "fox_b", "fox_c"]);
+            }
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn weighted_ranking_lets_popularity_beat_similarity() {
+            let Some(db) = TestDb::new().await else {
+                return;
+            };
+            // "furniture" is the closer of the two to "furniturr"
+            db.tags(&[("furniture", 100, 0), ("old_furniture", 5000, 0)])
+                .await;
+            let top = TagSearch {
+                limit: 1,
+                ..search("furniturr")
+            };
+            let matches = db.get_tags(&top, &strategy()).await;
+            assert_eq!(names(&matches), ["furniture"]);
+            assert!(matches!(matches.stage, Some(Stage::B)));
+            let weighted = Strategy {
+                weighted_ranking: true,
+                ..strategy()
+            };
+            let matches = db.get_tags(&top, &weighted).await;
+            assert_eq!(names(&matches), ["old_furniture"]);
+            db.finish().await;
+        }
+
+        #[actix_web::test]
+        async fn word_similarity_catches_typos_in_longer_names() {
+            let Some(db) = TestDb::new().await else {
+                return;
+            };
+            // too unlike "wolff" as a whole for stage B, but each has a word like it
+            db.tags(&[("wolfhound_puppy", 300, 5), ("big_wolf_costume", 20, 0)])
+                .await;
+            let matches = db.get_tags(&search("wolff"), &strategy()).await;
+            assert!(matches.tags.is_empty());
+            assert!(matches.stage.is_none());
+            let fallback = Strategy {
+                trigram_fallback: true,
+                ..strategy()
+            };
+            let matches = db.get_tags(&search("wolff"), &fallback).await;
+            assert_eq!(names(&matches), ["wolfhound_puppy", "big_wolf_costume"]);
+            assert!(matches!(matches.stage, Some(Stage::C)));
+            db.finish().await;
+        }
+    }
+}
+
+/// What gets cached per search: the serialized results plus whatever else is
+/// needed to rebuild the response.
+#[derive(Clone)]
+struct CacheEntry {
+    body: String,
+    /// Number of results in `body`.
+    count: usize,
+    /// Total number of matches across all pages, for paginated searches.
+    total: Option<i64>,
+    /// Hash of `body`, computed once so cache hits can answer conditional requests cheaply.
+    etag: u64,
+    /// The query stage the results came from.
+    stage: Option<db::Stage>,
+    /// How long the query for them took.
+    query_time: Duration,
+    inserted_at: Instant,
+    /// When the results were fetched, to the second like HTTP dates, for
+    /// `Last-Modified`.
+    modified: SystemTime,
+}
+
+impl CacheEntry {
+    fn new(body: String, count: usize, total: Option<i64>) -> Self {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        body.hash(&mut hasher);
+        CacheEntry {
+            body,
+            count,
+            total,
+            etag: hasher.finish(),
+            stage: None,
+            query_time: Duration::ZERO,
+            inserted_at: Instant::now(),
+            modified: UNIX_EPOCH
+                + Duration::from_secs(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ),
+        }
+    }
+
+    fn empty(format: Format) -> Self {
+        CacheEntry::new(format.serialize::<Tag>(&[]), 0, None)
+    }
+
+    /// A weak ETag for the response, since the same one goes out whether or
+    /// not the body is compressed; JSONP responses are a different
+    /// representation, so the callback is part of it.
+    fn etag(&self, callback: Option<&str>) -> String {
+        match callback {
+            Some(callback) => format!("W/\"{:016x}-{callback}\"", self.etag),
+            None => format!("W/\"{:016x}\"", self.etag),
+        }
+    }
+}
+
+/// Expires empty results sooner than populated ones, so a newly created tag
+/// isn't hidden for the full TTL by an earlier miss.
+struct CacheExpiry {
+    ttl: Duration,
+    empty_ttl: Duration,
+}
+
+impl CacheExpiry {
+    fn ttl_for(&self, entry: &CacheEntry) -> Duration {
+        if entry.count == 0 {
+            self.empty_ttl
+        } else {
+            self.ttl
+        }
+    }
+}
+
+impl Expiry<TagSearch, CacheEntry> for CacheExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &TagSearch,
+        value: &CacheEntry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.ttl_for(value))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &TagSearch,
+        value: &CacheEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.ttl_for(value))
+    }
+}
+
+/// Entry count and total weight of a cache, the latter by `CACHE_MAX_BYTES`'s
+/// weigher if there is one.
+struct CacheSize {
+    entries: u64,
+    weight: u64,
+}
+
+/// What [`CacheBackend::get_or_fetch`] came up with.
+enum Lookup {
+    Hit(CacheEntry),
+    /// Fetched for this request, and cached if it was to be kept.
+    Miss(CacheEntry),
+}
+
+/// Fetches an entry for a cache miss, along with whether it's worth keeping.
+type Fetch<'a> =
+    Pin<Box<dyn Future<Output = Result<(CacheEntry, bool), AutocompleteError>> + Send + 'a>>;
+
+/// Where search results are cached. Handlers only go through this, so a store
+/// other than the default in-process [`MokaCache`] only has to implement it.
+#[async_trait]
+trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &TagSearch) -> Option<CacheEntry>;
+
+    async fn insert(&self, key: TagSearch, entry: CacheEntry);
+
+    async fn invalidate(&self, key: &TagSearch);
+
+    async fn invalidate_all(&self);
+
+    /// Drops the entries for every search of `prefix`, whatever its other options.
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<(), String>;
+
+    async fn size(&self) -> CacheSize;
+
+    /// The entry for `key`, or else the one `fetch` comes up with, cached if
+    /// it's to be kept. This runs `fetch` for every concurrent miss; backends
+    /// that can should have them wait on a single one instead.
+    async fn get_or_fetch(
+        &self,
+        key: &TagSearch,
+        fetch: Fetch<'_>,
+    ) -> Result<Lookup, AutocompleteError> {
+        if let Some(x) = self.get(key).await {
+            return Ok(Lookup::Hit(x));
+        }
+        let (entry, keep) = fetch.await?;
+        if keep {
+            self.insert(key.clone(), entry.clone()).await;
+        }
+        Ok(Lookup::Miss(entry))
+    }
+}
+
+/// The default backend, local to the process.
+struct MokaCache(Cache<TagSearch, CacheEntry>);
+
+impl MokaCache {
+    /// Sized and expiring as configured, counting evictions in `evictions`.
+    fn new(config: &config::Config, evictions: prometheus::IntCounter) -> Self {
+        use moka::future::CacheBuilder;
+        // by bytes when configured, since entries' sizes vary a lot with the limit
+        // and the fields asked for
+        let mut cache = CacheBuilder::new(if config.cache_max_bytes > 0 {
+            config.cache_max_bytes
+        } else {
+            config.cache_max_capacity
+        });
+        if config.cache_max_bytes > 0 {
+            cache = cache.weigher(|key: &TagSearch, value: &CacheEntry| {
+                u32::try_from(key.prefix.len() + value.body.len()).unwrap_or(u32::MAX)
+            });
+        }
+        let cache = cache
+            .expire_after(CacheExpiry {
+                ttl: Duration::from_secs(config.cache_ttl_secs),
+                empty_ttl: Duration::from_secs(config.cache_empty_ttl_secs),
+            })
+            .support_invalidation_closures()
+            // purges and replacements aren't evictions, only expiry and size are
+            .eviction_listener(move |key: Arc<TagSearch>, _, cause| {
+                if cause.was_evicted() {
+                    evictions.inc();
+                    debug!("evicted {:?} from the cache: {:?}", key.prefix, cause);
+                }
+            })
+            .build();
+        MokaCache(cache)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MokaCache {
+    async fn get(&self, key: &TagSearch) -> Option<CacheEntry> {
+        self.0.get(key).await
+    }
+
+    async fn insert(&self, key: TagSearch, entry: CacheEntry) {
+        self.0.insert(key, entry).await;
+    }
+
+    async fn invalidate(&self, key: &TagSearch) {
+        self.0.invalidate(key).await;
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all();
+    }
+
+    async fn invalidate_prefix(&self, prefix: &str) -> Result<(), String> {
+        let prefix = prefix.to_owned();
+        self.0
+            .invalidate_entries_if(move |key, _| key.prefix == prefix)
+            .map(|_| ())
+            .map_err(|x| x.to_string())
+    }
+
+    async fn size(&self) -> CacheSize {
+        // flush pending bookkeeping so the counts are current
+        self.0.run_pending_tasks().await;
+        CacheSize {
+            entries: self.0.entry_count(),
+            weight: self.0.weighted_size(),
+        }
+    }
+
+    async fn get_or_fetch(
+        &self,
+        key: &TagSearch,
+        fetch: Fetch<'_>,
+    ) -> Result<Lookup, AutocompleteError> {
+        if let Some(x) = self.0.get(key).await {
+            return Ok(Lookup::Hit(x));
+        }
+        // concurrent misses for the same search wait their turn, and find the
+        // entry the first one put in; ones not to keep are handed back around
+        // the cache instead
+        let mut unkept = None;
+        let computed = self
+            .0
+            .entry_by_ref(key)
+            .and_try_compute_with(|existing| {
+                let unkept = &mut unkept;
+                async move {
+                    if existing.is_some() {
+                        return Ok(Op::Nop);
+                    }
+                    match fetch.await? {
+                        (entry, true) => Ok(Op::Put(entry)),
+                        (entry, false) => {
+                            *unkept = Some(entry);
+                            Ok(Op::Nop)
+                        }
+                    }
+                }
+            })
+            .await?;
+        match (computed, unkept) {
+            (CompResult::Unchanged(x), None) => Ok(Lookup::Hit(x.into_value())),
+            (CompResult::Inserted(x), None) => Ok(Lookup::Miss(x.into_value())),
+            (_, Some(entry)) => Ok(Lookup::Miss(entry)),
+            // nothing else is asked for
+            _ => Err(AutocompleteError::ServerError),
+        }
+    }
+}
+
+struct AutocompleteState {
+    pool: Pool,
+    /// Read replicas, taken in turn for searches. Empty means everything goes to `pool`.
+    replicas: Vec<Pool>,
+    next_replica: AtomicUsize,
+    /// Keyed by the whole search, so entries for different categories, limits
+    /// and so on never collide.
+    cache: Box<dyn CacheBackend>,
+    /// Serialized tags for exact name lookups, by normalized name. Misses
+    /// aren't cached, so a new tag shows up right away.
+    tag_cache: Cache<String, String>,
+    metrics: metrics::Metrics,
+    short_prefix_empty: bool,
+    /// Allowed prefix length in characters, not counting wildcards.
+    prefix_len: RangeInclusive<usize>,
+    /// What whitespace and hyphens in prefixes are turned into, if anything.
+    word_separator: Option<char>,
+    lowercase: models::Lowercase,
+    min_post_count_default: i32,
+    /// In thousandths, like [`TagSearch::min_similarity`].
+    min_similarity_default: u16,
+    /// Whether tags without any posts can match.
+    include_zero_count: bool,
+    strategy: db::Strategy,
+    admin_token: Option<String>,
+    /// Whether validation failures are answered with a `200` carrying an error
+    /// code instead of a `400`.
+    soft_errors: bool,
+    /// Largest serialized result set worth caching; `0` caches everything.
+    max_cached_bytes: usize,
+    queries: db::Queries,
+    /// Whether `debug` requests get their timings rather than being treated
+    /// like any other.
+    allow_debug: bool,
+    /// Whether responses say which query stage matched, for debugging.
+    match_stage_header: bool,
+    /// Ceiling on the `timeout_ms` a request may ask for.
+    max_statement_timeout_ms: u64,
+    /// `Cache-Control` value for successful autocomplete responses.
+    cache_control: String,
+    /// Answer searches without results with a bodyless `204`.
+    empty_as_204: bool,
+    /// `Cache-Control` value for responses without results, `204` or not.
+    empty_cache_control: String,
+    /// Age after which a cached entry is still served but refreshed in the background.
+    soft_ttl: Option<Duration>,
+    /// Cache keys with a background refresh in flight.
+    revalidating: Mutex<HashSet<TagSearch>>,
+    pool_acquire_timeout: Duration,
+    /// Bounds how many searches query the database at once, independently of
+    /// the pool size. `None` means unbounded.
+    db_permits: Option<Semaphore>,
+    db_permit_timeout: Duration,
+    /// Proxies whose `X-Forwarded-For` entries are believed.
+    trusted_proxies: Vec<Cidr>,
+    /// The OpenAPI document, rendered once for the base path.
+    openapi: String,
+    /// See [`TagSearch::cache_version`].
+    cache_version: Arc<str>,
+}
+
+impl AutocompleteState {
+    /// Sets up caches, metrics and settings from `config`, which `main` has
+    /// already validated, around connected pools and loaded queries.
+    fn new(config: &config::Config, pool: Pool, replicas: Vec<Pool>, queries: db::Queries) -> Self {
+        use moka::future::CacheBuilder;
+        let metrics = metrics::Metrics::new().expect("Failed to register metrics");
+        let cache = MokaCache::new(config, metrics.cache_evictions.clone());
+        let tag_cache = CacheBuilder::new(config.cache_max_capacity)
+            .time_to_live(Duration::from_secs(config.cache_ttl_secs))
+            .build();
+
+        AutocompleteState {
+            pool,
+            replicas,
+            next_replica: AtomicUsize::new(0),
+            cache: Box::new(cache),
+            tag_cache,
+            metrics,
+            short_prefix_empty: config.short_prefix_empty,
+            prefix_len: config.min_prefix_len..=config.max_prefix_len,
+            word_separator: config.word_separator,
+            lowercase: config.lowercase,
+            min_post_count_default: config.min_post_count_default,
+            min_similarity_default: match config.min_similarity {
+                x if (0.0..=1.0).contains(&x) => thousandths(x),
+                _ => panic!("MIN_SIMILARITY must be between 0 and 1"),
+            },
+            include_zero_count: config.include_zero_count,
+            strategy: db::Strategy {
+                trigram_fallback: config.trigram_fallback,
+                weighted_ranking: config.weighted_ranking,
+                fill_from_fallback: config.fill_from_fallback,
+                allowed_categories: (!config.allowed_categories.is_empty()).then(|| {
+                    config
+                        .allowed_categories
+                        .iter()
+                        .map(|x| x.parse())
+                        .collect::<Result<_, _>>()
+                        .expect("Failed to parse ALLOWED_CATEGORIES")
+                }),
+                prefix_table_max_len: config.prefix_table_max_len,
+            },
+            admin_token: config.admin_token.clone(),
+            cache_control: format!("public, max-age={}", config.response_max_age_secs()),
+            empty_as_204: config.empty_as_204,
+            soft_errors: config.soft_errors,
+            match_stage_header: config.match_stage_header,
+            allow_debug: config.allow_debug,
+            queries,
+            max_cached_bytes: config.max_cached_bytes,
+            max_statement_timeout_ms: config.max_statement_timeout_ms,
+            empty_cache_control: format!(
+                "public, max-age={}",
+                config
+                    .cache_empty_ttl_secs
+                    .min(config.response_max_age_secs())
+            ),
+            soft_ttl: (config.cache_soft_ttl_secs > 0)
+                .then(|| Duration::from_secs(config.cache_soft_ttl_secs)),
+            revalidating: Mutex::new(HashSet::new()),
+            pool_acquire_timeout: Duration::from_millis(config.pool_acquire_timeout_ms),
+            db_permits: (config.max_concurrent_queries > 0)
+                .then(|| Semaphore::new(config.max_concurrent_queries)),
+            db_permit_timeout: Duration::from_millis(config.query_permit_timeout_ms),
+            trusted_proxies: config
+                .trusted_proxies
+                .iter()
+                .map(|x| x.parse())
+                .collect::<Result<_, _>>()
+                .expect("Failed to parse TRUSTED_PROXIES"),
+            openapi: openapi::document(&config.base_path()).to_string(),
+            cache_version: config.cache_version.as_str().into(),
+        }
+    }
+
+    /// Whether an entry is small enough to keep in the cache. Oversized ones are
+    /// still served, just queried afresh every time.
+    fn cacheable(&self, search: &TagSearch, entry: &CacheEntry) -> bool {
+        if self.max_cached_bytes == 0 || entry.body.len() <= self.max_cached_bytes {
+            return true;
+        }
+        debug!(
+            "not caching {} bytes of results for {:?}",
+            entry.body.len(),
+            search.prefix
+        );
+        false
+    }
+
+    /// A connection for running searches: the next replica in turn, falling
+    /// through to the others and finally the primary if it can't hand one out.
+    /// A replica that refuses connections is passed over straight away, but
+    /// one that doesn't answer at all costs up to `pool_acquire_timeout`
+    /// before the next is tried.
+    async fn client(&self) -> Result<deadpool_postgres::Client, AutocompleteError> {
+        let start = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.replicas.len() {
+            let replica = &self.replicas[(start + i) % self.replicas.len()];
+            if let Ok(x) = self.acquire(replica, "replica").await {
+                return Ok(x);
+            }
+        }
+        self.acquire(&self.pool, "primary").await
+    }
+
+    /// Waits at most `pool_acquire_timeout` for a connection, so an exhausted
+    /// pool fails fast with a 503 instead of tying up the worker.
+    async fn acquire(
+        &self,
+        pool: &Pool,
+        name: &str,
+    ) -> Result<deadpool_postgres::Client, AutocompleteError> {
+        match actix_web::rt::time::timeout(self.pool_acquire_timeout, pool.get()).await {
+            Ok(Ok(x)) => Ok(x),
+            // every connection is busy, which clears up by itself
+            Ok(Err(deadpool_postgres::PoolError::Timeout(_))) | Err(_) => {
+                error!("timed out waiting for a {} connection", name);
+                Err(AutocompleteError::PoolExhausted)
+            }
+            Ok(Err(x)) => {
+                error!("{} unavailable: {}", name, x);
+                Err(AutocompleteError::ServerError)
+            }
+        }
+    }
+
+    /// The post count a search's tags need, which is never below one unless
+    /// unused tags are included.
+    fn min_post_count(&self, requested: Option<i32>) -> i32 {
+        let floor = if self.include_zero_count { 0 } else { 1 };
+        requested.unwrap_or(self.min_post_count_default).max(floor)
+    }
+
+    /// A search's [`TagSearch::min_similarity`], rejecting one outside
+    /// `0..=1`. Only stage C uses it, so searches that can't reach that stage
+    /// all get `0` and share a cache key.
+    fn min_similarity(
+        &self,
+        requested: Option<f64>,
+        prefix_only: bool,
+    ) -> Result<u16, AutocompleteError> {
+        let min_similarity = match requested {
+            Some(x) if (0.0..=1.0).contains(&x) => thousandths(x),
+            Some(_) => return Err(AutocompleteError::BadRequest),
+            None => self.min_similarity_default,
+        };
+        match self.strategy.trigram_fallback && !prefix_only {
+            true => Ok(min_similarity),
+            false => Ok(0),
+        }
+    }
+
+    /// A search for `prefix` with every option at its default.
+    fn search(&self, prefix: String) -> TagSearch {
+        TagSearch {
+            prefix,
+            limit: DEFAULT_LIMIT,
+            category: None,
+            order: Order::default(),
+            min_post_count: self.min_post_count(None),
+            min_similarity: self.min_similarity(None, false).unwrap_or_default(),
+            offset: None,
+            resolve_aliases: false,
+            prefix_only: false,
+            category_names: false,
+            annotate_exact: false,
+            group_by_category: false,
+            language: None,
+            format: Format::default(),
+            fields: None,
+            cache_version: self.cache_version.clone(),
+        }
+    }
+}
+
+/// How long clients are told to back off when the pool is exhausted; busy
+/// connections free up quickly.
+const POOL_RETRY_AFTER_SECS: u64 = 1;
+
+/// The same for requests shed under load, which is usually a short spike.
+const SHED_RETRY_AFTER_SECS: u64 = 1;
+
+#[derive(Clone, Debug, Display, Error, From)]
+enum AutocompleteError {
+    #[display(fmt = "bad request")]
+    BadRequest,
+    #[display(fmt = "bad request")]
+    PrefixTooShort,
+    #[display(fmt = "bad request")]
+    PrefixTooLong,
+    /// More wildcards than [`MAX_WILDCARDS`].
+    #[display(fmt = "bad request")]
+    InvalidPrefix,
+    #[display(fmt = "internal error")]
+    ServerError,
+    #[display(fmt = "service unavailable")]
+    ServiceUnavailable,
+    /// No pooled connection freed up in time.
+    #[display(fmt = "unavailable")]
+    PoolExhausted,
+    /// More than `max_in_flight_requests` were already being handled.
+    #[display(fmt = "overloaded")]
+    Overloaded,
+    /// Carries the number of seconds the client should wait before retrying.
+    #[display(fmt = "too many requests")]
+    #[from(ignore)]
+    TooManyRequests(#[error(not(source))] u64),
+    #[display(fmt = "unauthorized")]
+    Unauthorized,
+    #[display(fmt = "not found")]
+    NotFound,
+    #[display(fmt = "gateway timeout")]
+    GatewayTimeout,
+}
+
+impl error::ResponseError for AutocompleteError {
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponseBuilder::new(self.status_code());
+        res.insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+            .insert_header((header::CACHE_CONTROL, "private; max-age=0"));
+        match *self {
+            AutocompleteError::PoolExhausted => {
+                res.insert_header((header::RETRY_AFTER, POOL_RETRY_AFTER_SECS.to_string()))
+            }
+            AutocompleteError::Overloaded => {
+                res.insert_header((header::RETRY_AFTER, SHED_RETRY_AFTER_SECS.to_string()))
+            }
+            AutocompleteError::TooManyRequests(retry_after) => {
+                res.insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            }
+            _ => &mut res,
+        };
+        res.body(serde_json::json!({ "error": self.to_string(), "code": self.code() }).to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            AutocompleteError::BadRequest
+            | AutocompleteError::PrefixTooShort
+            | AutocompleteError::PrefixTooLong
+            | AutocompleteError::InvalidPrefix => StatusCode::BAD_REQUEST,
+            AutocompleteError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            AutocompleteError::ServiceUnavailable
+            | AutocompleteError::PoolExhausted
+            | AutocompleteError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            AutocompleteError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AutocompleteError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AutocompleteError::NotFound => StatusCode::NOT_FOUND,
+            AutocompleteError::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl AutocompleteError {
+    /// The stable code sent alongside the message, for clients to branch on.
+    fn code(&self) -> &'static str {
+        match *self {
+            AutocompleteError::BadRequest => "BAD_REQUEST",
+            AutocompleteError::PrefixTooShort => "PREFIX_TOO_SHORT",
+            AutocompleteError::PrefixTooLong => "PREFIX_TOO_LONG",
+            AutocompleteError::InvalidPrefix => "INVALID_PREFIX",
+            AutocompleteError::ServerError => "INTERNAL_ERROR",
+            AutocompleteError::ServiceUnavailable => "SERVICE_UNAVAILABLE",
+            AutocompleteError::PoolExhausted => "POOL_EXHAUSTED",
+            AutocompleteError::Overloaded => "OVERLOADED",
+            AutocompleteError::TooManyRequests(_) => "RATE_LIMITED",
+            AutocompleteError::Unauthorized => "UNAUTHORIZED",
+            AutocompleteError::NotFound => "NOT_FOUND",
+            AutocompleteError::GatewayTimeout => "QUERY_TIMEOUT",
+        }
+    }
+
+    /// The code a validation failure is reported under with `soft_errors`.
+    /// Anything that isn't the client's doing has none and stays an error.
+    fn soft_code(&self) -> Option<&'static str> {
+        match *self {
+            AutocompleteError::PrefixTooShort => Some("too_short"),
+            AutocompleteError::BadRequest
+            | AutocompleteError::PrefixTooLong
+            | AutocompleteError::InvalidPrefix => Some("invalid"),
+            _ => None,
+        }
+    }
+}
+
+/// The `200` a validation failure is answered with under `soft_errors`.
+fn soft_error_response(code: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, "private; max-age=0"))
+        .body(format!("{{\"results\":[],\"error\":\"{code}\"}}"))
+}
+
+/// Answers a request whose parameters couldn't be extracted, which is a
+/// validation failure like any other.
+fn extractor_error<E>(err: E, req: &HttpRequest) -> actix_web::Error
+where
+    E: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    match req.app_data::<Data<AutocompleteState>>() {
+        Some(data) if data.soft_errors => {
+            error::InternalError::from_response(err, soft_error_response("invalid")).into()
+        }
+        _ => AutocompleteError::BadRequest.into(),
+    }
+}
+
+/// Logs a failed query with its classification and maps it to the error the
+/// client sees, which never includes the details.
+fn query_error(x: db::DbError) -> AutocompleteError {
+    error!(kind = x.kind(), sql_state = x.sql_state().unwrap_or_default(); "query failed: {}", x);
+    if x.is_timeout() {
+        AutocompleteError::GatewayTimeout
+    } else {
+        AutocompleteError::ServerError
+    }
+}
+
+const MAX_WILDCARDS: usize = 1;
+
+/// NFC-normalizes and lowercases (see [`models::Lowercase`]) a tag name, dropping control and invisible
+/// formatting characters such as NULs, zero-width spaces and BOMs. Inner
+/// whitespace and hyphens become the word separator when one is configured,
+/// and whitespace is dropped otherwise.
+fn normalize(data: &AutocompleteState, tag: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+    use unicode_properties::{GeneralCategory, UnicodeGeneralCategory};
+    // before NFC, so that characters they separated can still compose; tabs and
+    // newlines are controls too but are handled as whitespace below
+    let visible: String = tag
+        .chars()
+        .filter(|x| {
+            x.is_whitespace()
+                || !matches!(
+                    x.general_category(),
+                    GeneralCategory::Control | GeneralCategory::Format
+                )
+        })
+        .collect();
+    data.lowercase
+        .apply(&visible.trim().nfc().collect::<String>())
+        .chars()
+        .filter_map(|x| match data.word_separator {
+            Some(separator) if x.is_whitespace() || x == '-' => Some(separator),
+            _ if x.is_whitespace() => None,
+            _ => Some(x),
+        })
+        .collect()
+}
+
+fn validate_transform_tag(
+    data: &AutocompleteState,
+    tag: &str,
+) -> Result<String, AutocompleteError> {
+    let prefix_len = &data.prefix_len;
+    // coarse guard against normalizing huge inputs; the real bounds are checked
+    // on the normalized string, since NFC and whitespace removal change the count
+    let max_raw_len = prefix_len.end() * 4;
+    if tag.chars().take(max_raw_len + 1).count() > max_raw_len {
+        return Err(AutocompleteError::PrefixTooLong);
+    }
+    let tag_str = normalize(data, tag).replace('%', "");
+    // a `*` is a wildcard; more than a few make for pathological LIKE patterns
+    if tag_str.matches('*').count() > MAX_WILDCARDS {
+        return Err(AutocompleteError::InvalidPrefix);
+    }
+    let len = tag_str.chars().filter(|x| *x != '*').count();
+    if len < *prefix_len.start() {
+        return Err(AutocompleteError::PrefixTooShort);
+    }
+    if len > *prefix_len.end() {
+        return Err(AutocompleteError::PrefixTooLong);
+    }
+    Ok(tag_str)
+}
+
+const DEFAULT_LIMIT: i64 = 10;
+const MAX_LIMIT: i64 = 50;
+/// The same for `stream`, which is for taking everything that matches.
+const MAX_STREAM_LIMIT: i64 = 5_000;
+const MAX_OFFSET: i64 = 500;
+
+#[derive(Deserialize)]
+struct Req {
+    #[serde(rename(deserialize = "search[name_matches]"))]
+    tag_prefix: Option<String>,
+    /// Shorter spelling of `search[name_matches]`, for generic autocomplete widgets.
+    q: Option<String>,
+    limit: Option<i64>,
+    category: Option<i16>,
+    resolve_aliases: Option<bool>,
+    prefix_only: Option<bool>,
+    category_names: Option<bool>,
+    annotate_exact: Option<bool>,
+    group_by_category: Option<bool>,
+    callback: Option<String>,
+    order: Option<Order>,
+    min_post_count: Option<i32>,
+    min_similarity: Option<f64>,
+    offset: Option<i64>,
+    format: Option<Format>,
+    fields: Option<String>,
+    timeout_ms: Option<u64>,
+    debug: Option<bool>,
+    nocache: Option<bool>,
+    stream: Option<bool>,
+    /// Every `search[name_matches]` when it's repeated, in which case
+    /// `tag_prefix` is unset.
+    #[serde(skip)]
+    prefixes: Vec<String>,
+}
+
+impl Req {
+    /// The prefix to complete, from `search[name_matches]` or else `q`.
+    fn prefix(&self) -> Result<&str, AutocompleteError> {
+        self.tag_prefix
+            .as_deref()
+            .or(self.q.as_deref())
+            .ok_or(AutocompleteError::BadRequest)
+    }
+}
+
+/// The `POST /` body; the same options as the query string, minus JSONP.
+#[derive(Deserialize)]
+struct JsonReq {
+    prefix: String,
+    limit: Option<i64>,
+    category: Option<i16>,
+    resolve_aliases: Option<bool>,
+    prefix_only: Option<bool>,
+    category_names: Option<bool>,
+    annotate_exact: Option<bool>,
+    group_by_category: Option<bool>,
+    order: Option<Order>,
+    min_post_count: Option<i32>,
+    min_similarity: Option<f64>,
+    offset: Option<i64>,
+    format: Option<Format>,
+    fields: Option<Vec<String>>,
+    timeout_ms: Option<u64>,
+    debug: Option<bool>,
+    nocache: Option<bool>,
+    stream: Option<bool>,
+}
+
+impl From<JsonReq> for Req {
+    fn from(x: JsonReq) -> Self {
+        Req {
+            tag_prefix: Some(x.prefix),
+            q: None,
+            limit: x.limit,
+            category: x.category,
+            resolve_aliases: x.resolve_aliases,
+            prefix_only: x.prefix_only,
+            category_names: x.category_names,
+            annotate_exact: x.annotate_exact,
+            group_by_category: x.group_by_category,
+            callback: None,
+            order: x.order,
+            min_post_count: x.min_post_count,
+            min_similarity: x.min_similarity,
+            offset: x.offset,
+            format: x.format,
+            fields: x.fields.map(|x| x.join(",")),
+            timeout_ms: x.timeout_ms,
+            debug: x.debug,
+            nocache: x.nocache,
+            stream: x.stream,
+            prefixes: Vec::new(),
+        }
+    }
+}
+
+/// Checks that a JSONP callback is a plain identifier (`[A-Za-z_$][A-Za-z0-9_$]*`),
+/// so it can't be used to inject script into the response.
+fn is_valid_callback(callback: &str) -> bool {
+    let mut chars = callback.chars();
+    matches!(chars.next(), Some(x) if x.is_ascii_alphabetic() || x == '_' || x == '$')
+        && chars.all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '$')
+}
+
+/// A validated search, i.e. everything that determines the response body.
+/// This is also the cache key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TagSearch {
+    prefix: String,
+    limit: i64,
+    category: Option<i16>,
+    order: Order,
+    min_post_count: i32,
+    /// The word similarity stage C matches need, in thousandths so that the
+    /// key stays hashable.
+    min_similarity: u16,
+    /// Set for paginated searches, which also report the total match count.
+    offset: Option<i64>,
+    resolve_aliases: bool,
+    category_names: bool,
+    annotate_exact: bool,
+    /// Results as an object of arrays keyed by category, rather than an array.
+    group_by_category: bool,
+    /// Skips the fuzzy stages, so only names or aliases starting with the
+    /// prefix match.
+    prefix_only: bool,
+    /// Only affects ordering by post count, so it's `None` for any other
+    /// order and those share a cache key whatever the client's language.
+    language: Option<Language>,
+    format: Format,
+    /// Restricts each result to these fields. `None` means all of them.
+    fields: Option<Vec<&'static str>>,
+    /// `CACHE_VERSION`, which doesn't change the results but is bumped when
+    /// what's cached should no longer be served, say after normalization
+    /// changes, without purging a shared cache.
+    cache_version: Arc<str>,
+}
+
+/// Turns a similarity in `0..=1` into [`TagSearch::min_similarity`]'s
+/// thousandths.
+fn thousandths(x: f64) -> u16 {
+    (x * 1000.0).round() as u16
+}
+
+/// Returns the serialized results for a search, from the cache when possible
+/// and from the database otherwise, along with whether it was a cache hit.
+async fn lookup(
+    data: &Data<AutocompleteState>,
+    search: &TagSearch,
+    statement_timeout_ms: Option<u64>,
+) -> Result<(CacheEntry, bool), AutocompleteError> {
+    let fetched = Box::pin(async move {
+        let entry = fetch(data, search, statement_timeout_ms, None).await?;
+        // oversized results are still served, just never cached
+        let keep = data.cacheable(search, &entry);
+        Ok((entry, keep))
+    });
+    let cached = match data.cache.get_or_fetch(search, fetched).await? {
+        Lookup::Hit(x) => x,
+        Lookup::Miss(x) => {
+            data.metrics.cache_misses.inc();
+            return Ok((x, false));
+        }
+    };
+    data.metrics.cache_hits.inc();
+    if data
+        .soft_ttl
+        .is_some_and(|x| cached.inserted_at.elapsed() > x)
+    {
+        revalidate(data, search.clone());
+    }
+    Ok((cached, true))
+}
+
+/// Refreshes a stale entry in the background while it goes on being served.
+/// Only one refresh per key runs at a time.
+fn revalidate(data: &Data<AutocompleteState>, search: TagSearch) {
+    let mut revalidating = data.revalidating.lock().unwrap_or_else(|x| x.into_inner());
+    if !revalidating.insert(search.clone()) {
+        return;
+    }
+    drop(revalidating);
+    let data = data.clone();
+    actix_web::rt::spawn(
+        async move {
+            // errors are already logged by fetch; the stale entry stays until it expires
+            match fetch(&data, &search, None, None).await {
+                Ok(entry) if data.cacheable(&search, &entry) => {
+                    data.cache.insert(search.clone(), entry).await;
+                }
+                Ok(_) => data.cache.invalidate(&search).await,
+                Err(_) => {}
+            }
+            data.revalidating
+                .lock()
+                .unwrap_or_else(|x| x.into_inner())
+                .remove(&search);
+        }
+        .in_current_span(),
+    );
+}
+
+/// Where a search's time went, for `debug` responses.
+#[derive(Default)]
+struct Diagnostics {
+    /// Waiting for a pooled connection.
+    pool: Duration,
+    stages: Vec<(db::Stage, Duration)>,
+}
+
+/// Runs a search against the database and serializes the results, optionally
+/// under a `statement_timeout` other than the configured one and noting where
+/// the time went.
+async fn fetch(
+    data: &AutocompleteState,
+    search: &TagSearch,
+    statement_timeout_ms: Option<u64>,
+    diagnostics: Option<&mut Diagnostics>,
+) -> Result<CacheEntry, AutocompleteError> {
+    let (matches, query_time) = query(data, search, statement_timeout_ms, diagnostics).await?;
+    Ok(CacheEntry {
+        stage: matches.stage,
+        query_time,
+        ..CacheEntry::new(
+            serialize_results(search, &matches.tags),
+            matches.tags.len(),
+            matches.total,
+        )
+    })
+}
+
+/// The querying half of [`fetch`], which leaves the ranked tags as they are
+/// and says how long the database took.
+async fn query(
+    data: &AutocompleteState,
+    search: &TagSearch,
+    statement_timeout_ms: Option<u64>,
+    mut diagnostics: Option<&mut Diagnostics>,
+) -> Result<(db::Matches, Duration), AutocompleteError> {
+    // held until the query is done; cache hits never get here
+    let _permit = match &data.db_permits {
+        Some(permits) => {
+            match actix_web::rt::time::timeout(data.db_permit_timeout, permits.acquire()).await {
+                Ok(Ok(x)) => Some(x),
+                _ => {
+                    error!("too many concurrent queries, shedding request");
+                    return Err(AutocompleteError::ServiceUnavailable);
+                }
+            }
+        }
+        None => None,
+    };
+    let span = tracing::debug_span!(
+        "get_tags",
+        stage = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    );
+    // a replica recycling its connections can close one under us; it's taken
+    // out of the pool, which may not have noticed yet, so a second attempt
+    // gets a working one
+    let mut retried = false;
+    let mut query_time = Duration::ZERO;
+    let matches = loop {
+        let acquiring = Instant::now();
+        let mut client = data.client().await?;
+        if let Some(diagnostics) = diagnostics.as_deref_mut() {
+            diagnostics.pool += acquiring.elapsed();
+        }
+        let db_timer = data.metrics.db_query_duration.start_timer();
+        let results = match statement_timeout_ms {
+            Some(ms) => {
+                db::get_tags_with_timeout(&mut client, ms, search, &data.strategy, &data.queries)
+                    .instrument(span.clone())
+                    .await
+            }
+            None => {
+                db::get_tags(&client, search, &data.strategy, &data.queries)
+                    .instrument(span.clone())
+                    .await
+            }
+        };
+        let elapsed = db_timer.stop_and_record();
+        span.record("duration_ms", elapsed * 1000.0);
+        query_time += Duration::from_secs_f64(elapsed);
+        match results {
+            Err(x) if x.is_closed() && !retried => {
+                warn!("connection closed mid-query, retrying: {}", x);
+                drop(deadpool_postgres::Client::take(client));
+                retried = true;
+            }
+            x => break x.map_err(query_error)?,
+        }
+    };
+    if let Some(stage) = matches.stage {
+        span.record("stage", stage.as_str());
+    }
+    if let Some(diagnostics) = diagnostics {
+        diagnostics.stages.clone_from(&matches.timings);
+    }
+    let mut matches = matches;
+    // the fuzzy stages' similarity ranking isn't in the tags, so their ties
+    // can't be told apart from the rest
+    if let (Some(language), Order::Count) = (search.language, search.order) {
+        language.rank(&mut matches.tags[..matches.from_stage_a]);
+    }
+    Ok((matches, query_time))
+}
+
+/// A tag with the annotations `search` asked for.
+fn annotate<'a>(search: &'a TagSearch, tag: &'a Tag) -> AnnotatedTag<'a> {
+    AnnotatedTag::new(
+        tag,
+        search.resolve_aliases,
+        search.category_names,
+        search.annotate_exact.then_some(search.prefix.as_str()),
+    )
+}
+
+/// The response body for a search's results, in its format and shape.
+fn serialize_results(search: &TagSearch, results: &[Tag]) -> String {
+    let serialize = |tags: &[&Tag]| {
+        let annotated = tags.iter().map(|x| annotate(search, x)).collect::<Vec<_>>();
+        match &search.fields {
+            Some(fields) => search
+                .format
+                .serialize(&models::project(&annotated, fields)),
+            None => search.format.serialize(&annotated),
+        }
+    };
+    if search.group_by_category {
+        // groups come in the order of their first tag, keeping the ranking
+        let mut groups: Vec<(String, Vec<&Tag>)> = Vec::new();
+        for tag in results {
+            let key = match models::category_name(tag.category) {
+                Some(name) if search.category_names => name.to_owned(),
+                _ => tag.category.to_string(),
+            };
+            match groups.iter_mut().find(|(x, _)| *x == key) {
+                Some((_, tags)) => tags.push(tag),
+                None => groups.push((key, vec![tag])),
+            }
+        }
+        let members = groups
+            .iter()
+            .map(|(key, tags)| {
+                format!(
+                    "{}:{}",
+                    serde_json::Value::from(key.as_str()),
+                    serialize(tags)
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{{}}}", members.join(","))
+    } else {
+        serialize(&results.iter().collect::<Vec<_>>())
+    }
+}
+
+/// One result as [`serialize_results`] would put it in a JSON array.
+fn serialize_result(search: &TagSearch, tag: &Tag) -> String {
+    let annotated = annotate(search, tag);
+    let value = match &search.fields {
+        Some(fields) => models::project(&[annotated], fields).pop(),
+        None => serde_json::to_value(&annotated).ok(),
+    };
+    value.map(|x| x.to_string()).unwrap_or_default()
+}
+
+// HEAD is answered by the same handler so it gets identical status and headers;
+// actix drops the body. `/autocomplete` is for gateways that route by path prefix
+#[routes]
+#[get("/")]
+#[head("/")]
+#[get("/autocomplete")]
+#[head("/autocomplete")]
+async fn autocomplete(
+    data: web::Data<AutocompleteState>,
+    http_req: HttpRequest,
+    request_id: Option<web::ReqData<RequestId>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // `Req` can only take one of each parameter, so repeated prefixes are
+    // pulled out first and the rest extracted as usual
+    let (prefixes, rest) = split_repeated_prefixes(http_req.query_string());
+    let query = match prefixes.len() {
+        0 | 1 => http_req.query_string(),
+        _ => rest.as_str(),
+    };
+    let mut req = web::Query::<Req>::from_query(query)
+        .map_err(|x| extractor_error(x, &http_req))?
+        .into_inner();
+    if prefixes.len() > 1 {
+        req.prefixes = prefixes;
+    }
+    Ok(handle(&data, &req, &http_req, request_id).await?)
+}
+
+/// Separates the `search[name_matches]` values in a query string from the rest
+/// of it, which is left as it was.
+fn split_repeated_prefixes(query: &str) -> (Vec<String>, String) {
+    let mut prefixes = Vec::new();
+    let mut rest = Vec::new();
+    for pair in query.split('&') {
+        // decoded one pair at a time, since the key may be percent-encoded too
+        let decoded = web::Query::<Vec<(String, String)>>::from_query(pair)
+            .ok()
+            .and_then(|x| x.into_inner().pop());
+        match decoded {
+            Some((key, value)) if key == "search[name_matches]" => prefixes.push(value),
+            _ => rest.push(pair),
+        }
+    }
+    (prefixes, rest.join("&"))
+}
+
+// for prefixes and filter sets that don't fit comfortably in a query string
+#[routes]
+#[post("/")]
+#[post("/autocomplete")]
+async fn autocomplete_json(
+    data: web::Data<AutocompleteState>,
+    req: web::Json<JsonReq>,
+    http_req: HttpRequest,
+    request_id: Option<web::ReqData<RequestId>>,
+) -> Result<HttpResponse, AutocompleteError> {
+    handle(&data, &req.into_inner().into(), &http_req, request_id).await
+}
+
+/// Answers an autocomplete request, recording metrics and the access log line.
+async fn handle(
+    data: &Data<AutocompleteState>,
+    req: &Req,
+    http_req: &HttpRequest,
+    request_id: Option<web::ReqData<RequestId>>,
+) -> Result<HttpResponse, AutocompleteError> {
+    data.metrics.requests.inc();
+    let start = Instant::now();
+    let mut access = AccessLog::default();
+    let res = match complete(data, req, http_req, &mut access).await {
+        Err(x) if data.soft_errors => match x.soft_code() {
+            Some(code) => Ok(soft_error_response(code)),
+            None => Err(x),
+        },
+        x => x,
+    };
+    let status = match &res {
+        Ok(x) => x.status(),
+        Err(x) => error::ResponseError::status_code(x),
+    };
+    let latency = start.elapsed();
+    let cache = if access.cache_hit { "hit" } else { "miss" };
+    data.metrics
+        .request_duration
+        .with_label_values(&[cache, status.as_str()])
+        .observe(latency.as_secs_f64());
+    let request_id = request_id.map(|x| x.into_inner().0).unwrap_or_default();
+    let client_ip = client_ip(http_req, &data.trusted_proxies)
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    let record = AccessRecord {
+        request_id: &request_id,
+        client_ip: &client_ip,
+        access: &access,
+        latency,
+        status,
+    };
+    logging::info("autocompleted::access", "autocomplete", &record);
+    res
+}
+
+/// What the access log records about a request. Only the normalized prefix is
+/// kept, never the raw query.
+#[derive(Default)]
+struct AccessLog {
+    prefix: String,
+    results: usize,
+    cache_hit: bool,
+    /// The stage that matched, if the request searched the database or cache.
+    stage: Option<db::Stage>,
+    /// Time spent querying the database for this request.
+    query_time: Duration,
+}
+
+impl AccessLog {
+    fn record(&mut self, entry: &CacheEntry, cache_hit: bool) {
+        self.results += entry.count;
+        self.cache_hit = cache_hit;
+        self.stage = entry.stage;
+        if !cache_hit {
+            self.query_time += entry.query_time;
+        }
+    }
+}
+
+/// A request's access log line: what [`AccessLog`] gathered, who asked and
+/// how it went.
+struct AccessRecord<'a> {
+    request_id: &'a str,
+    client_ip: &'a str,
+    access: &'a AccessLog,
+    latency: Duration,
+    status: StatusCode,
+}
+
+impl log::kv::Source for AccessRecord<'_> {
+    fn visit<'kvs>(
+        &'kvs self,
+        visitor: &mut dyn log::kv::VisitSource<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        use log::kv::{Key, Value};
+        let ms = |x: Duration| Value::from(x.as_secs_f64() * 1000.0);
+        let access = self.access;
+        visitor.visit_pair(Key::from("request_id"), Value::from(self.request_id))?;
+        visitor.visit_pair(Key::from("client_ip"), Value::from(self.client_ip))?;
+        visitor.visit_pair(Key::from("prefix"), Value::from(access.prefix.as_str()))?;
+        visitor.visit_pair(Key::from("results"), Value::from(access.results))?;
+        visitor.visit_pair(Key::from("cache_hit"), Value::from(access.cache_hit))?;
+        let stage = access.stage.map_or("", db::Stage::as_str);
+        visitor.visit_pair(Key::from("stage"), Value::from(stage))?;
+        visitor.visit_pair(Key::from("duration_ms"), ms(access.query_time))?;
+        visitor.visit_pair(Key::from("latency_ms"), ms(self.latency))?;
+        visitor.visit_pair(Key::from("status"), Value::from(self.status.as_u16()))
+    }
+}
+
+async fn complete(
+    data: &Data<AutocompleteState>,
+    req: &Req,
+    http_req: &HttpRequest,
+    access: &mut AccessLog,
+) -> Result<HttpResponse, AutocompleteError> {
+    let callback = req.callback.as_deref();
+    if callback.is_some_and(|x| !is_valid_callback(x)) {
+        return Err(AutocompleteError::BadRequest);
+    }
+    let format = req.format.unwrap_or_else(|| negotiate_format(http_req));
+    // JSONP only makes sense around a JSON array, and groups are JSON arrays
+    if (callback.is_some() || req.group_by_category == Some(true)) && format != Format::Json {
+        return Err(AutocompleteError::BadRequest);
+    }
+    // streams are written a result at a time, which only arrays and lines allow
+    let stream = req.stream == Some(true);
+    if stream
+        && (callback.is_some()
+            || req.group_by_category == Some(true)
+            || req.debug == Some(true)
+            || format == Format::Compact)
+    {
+        return Err(AutocompleteError::BadRequest);
+    }
+    if !req.prefixes.is_empty() {
+        return complete_many(data, req, http_req, format, access).await;
+    }
+    let Some(search) = search_for(data, req, http_req, format, req.prefix()?)? else {
+        let vary = Vary {
+            accept: req.format.is_none(),
+            accept_language: false,
+        };
+        return Ok(results_response(
+            data,
+            http_req,
+            empty_results(req, format),
+            format,
+            callback,
+            vary,
+        ));
+    };
+    access.prefix.clone_from(&search.prefix);
+    let statement_timeout_ms = statement_timeout_ms(data, req);
+    let vary = Vary {
+        accept: req.format.is_none(),
+        accept_language: search.order == Order::Count,
+    };
+    // large and rare, so never cached
+    if stream {
+        let (matches, query_time) = query(data, &search, statement_timeout_ms, None).await?;
+        access.results = matches.tags.len();
+        access.stage = matches.stage;
+        access.query_time = query_time;
+        return Ok(streamed_response(data, search, matches, vary));
+    }
+    // always queried afresh, since the point is to see the query
+    if req.debug == Some(true) && data.allow_debug {
+        // the envelope is a JSON object, so the results have to be JSON too
+        if callback.is_some() || format == Format::Ndjson {
+            return Err(AutocompleteError::BadRequest);
+        }
+        let mut diagnostics = Diagnostics::default();
+        let entry = fetch(data, &search, statement_timeout_ms, Some(&mut diagnostics)).await?;
+        access.record(&entry, false);
+        return Ok(debug_response(&entry, &diagnostics));
+    }
+    // only for the admin, so public clients can't use it to defeat the cache
+    let (entry, cache_hit) = if req.nocache == Some(true) && authorize(data, http_req).is_ok() {
+        let entry = fetch(data, &search, statement_timeout_ms, None).await?;
+        if data.cacheable(&search, &entry) {
+            data.cache.insert(search.clone(), entry.clone()).await;
+        }
+        (entry, false)
+    } else {
+        lookup(data, &search, statement_timeout_ms).await?
+    };
+    access.record(&entry, cache_hit);
+    Ok(results_response(
+        data, http_req, entry, format, callback, vary,
+    ))
+}
+
+/// Answers a repeated `search[name_matches]` with a JSON object mapping each
+/// prefix to its results, as if each had been requested individually. Each
+/// prefix is cached on its own.
+async fn complete_many(
+    data: &Data<AutocompleteState>,
+    req: &Req,
+    http_req: &HttpRequest,
+    format: Format,
+    access: &mut AccessLog,
+) -> Result<HttpResponse, AutocompleteError> {
+    // ndjson isn't JSON, and there'd be no one X-Total-Count to report
+    let nestable = format != Format::Ndjson
+        && req.callback.is_none()
+        && req.offset.is_none()
+        && req.stream != Some(true);
+    if !nestable || req.prefixes.len() > MAX_BATCH_SIZE {
+        return Err(AutocompleteError::BadRequest);
+    }
+    // validate everything up front so a bad entry doesn't cost any queries
+    let searches = req
+        .prefixes
+        .iter()
+        .map(|x| Ok((x, search_for(data, req, http_req, format, x)?)))
+        .collect::<Result<Vec<_>, AutocompleteError>>()?;
+    let statement_timeout_ms = statement_timeout_ms(data, req);
+    let mut seen = HashSet::new();
+    let mut body = String::from("{");
+    let mut count = 0;
+    let mut modified = UNIX_EPOCH;
+    let mut cache_hit = true;
+    // only what was searched for, never the raw input
+    let mut logged = Vec::new();
+    for (input, search) in searches {
+        if !seen.insert(input) {
+            continue;
+        }
+        let entry = match search {
+            Some(search) => {
+                logged.push(search.prefix.clone());
+                let (entry, hit) = lookup(data, &search, statement_timeout_ms).await?;
+                cache_hit &= hit;
+                if !hit {
+                    access.query_time += entry.query_time;
+                }
+                entry
+            }
+            None => empty_results(req, format),
+        };
+        if body.len() > 1 {
+            body.push(',');
+        }
+        body.push_str(&serde_json::to_string(input).unwrap_or_default());
+        body.push(':');
+        body.push_str(&entry.body);
+        count += entry.count;
+        modified = modified.max(entry.modified);
+    }
+    body.push('}');
+    access.prefix = logged.join(",");
+    access.results = count;
+    access.cache_hit = cache_hit;
+    let vary = Vary {
+        accept: req.format.is_none(),
+        accept_language: req.order.unwrap_or_default() == Order::Count,
+    };
+    let entry = CacheEntry {
+        modified,
+        ..CacheEntry::new(body, count, None)
+    };
+    Ok(results_response(
+        data,
+        http_req,
+        entry,
+        Format::Json,
+        None,
+        vary,
+    ))
+}
+
+/// What a prefix too short to search for is answered with under
+/// `short_prefix_empty`.
+fn empty_results(req: &Req, format: Format) -> CacheEntry {
+    match req.group_by_category {
+        Some(true) => CacheEntry::new("{}".to_owned(), 0, None),
+        _ => CacheEntry::empty(format),
+    }
+}
+
+/// The `timeout_ms` a request asked for, within bounds. It only changes how
+/// long we wait, not the results, so it's not part of the search.
+fn statement_timeout_ms(data: &AutocompleteState, req: &Req) -> Option<u64> {
+    req.timeout_ms
+        .map(|x| x.clamp(1, data.max_statement_timeout_ms))
+}
+
+/// Validates one prefix and builds the search for it with the request's
+/// options, or returns `None` for a prefix too short to search for under
+/// `short_prefix_empty`.
+fn search_for(
+    data: &AutocompleteState,
+    req: &Req,
+    http_req: &HttpRequest,
+    format: Format,
+    input: &str,
+) -> Result<Option<TagSearch>, AutocompleteError> {
+    let (qualified_category, raw_prefix) = split_category_qualifier(input);
+    let prefix: String = match validate_transform_tag(data, raw_prefix) {
+        Err(AutocompleteError::PrefixTooShort) if data.short_prefix_empty => return Ok(None),
+        x => x?,
+    };
+    let category = req.category.or(qualified_category);
+    if category.is_some_and(|x| !data.strategy.allows(x)) {
+        return Err(AutocompleteError::BadRequest);
+    }
+    let max_limit = match req.stream {
+        Some(true) => MAX_STREAM_LIMIT,
+        _ => MAX_LIMIT,
+    };
+    let order = req.order.unwrap_or_default();
+    Ok(Some(TagSearch {
+        limit: req.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, max_limit),
+        category,
+        order,
+        min_post_count: data.min_post_count(req.min_post_count),
+        min_similarity: data
+            .min_similarity(req.min_similarity, req.prefix_only.unwrap_or(false))?,
+        offset: req.offset.map(|x| x.clamp(0, MAX_OFFSET)),
+        resolve_aliases: req.resolve_aliases.unwrap_or(false),
+        prefix_only: req.prefix_only.unwrap_or(false),
+        category_names: req.category_names.unwrap_or(false),
+        annotate_exact: req.annotate_exact.unwrap_or(false),
+        group_by_category: req.group_by_category.unwrap_or(false),
+        language: http_req
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .filter(|_| order == Order::Count)
+            .and_then(|x| x.to_str().ok())
+            .and_then(Language::negotiate),
+        format,
+        fields: req.fields.as_deref().and_then(models::parse_fields),
+        ..data.search(prefix)
+    }))
+}
+
+/// Wraps results in `{"results": ..., "timings": {...}, "stage": ...}`, with a
+/// `pool_ms` timing and one `stage_<x>_ms` per stage that ran.
+fn debug_response(entry: &CacheEntry, diagnostics: &Diagnostics) -> HttpResponse {
+    let ms = |x: Duration| serde_json::Value::from(x.as_secs_f64() * 1000.0);
+    let mut timings = serde_json::Map::new();
+    timings.insert("pool_ms".to_owned(), ms(diagnostics.pool));
+    for (stage, took) in &diagnostics.stages {
+        timings.insert(format!("stage_{}_ms", stage.as_str()), ms(*took));
+    }
+    let stage = serde_json::Value::from(entry.stage.map(db::Stage::as_str));
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(format!(
+            "{{\"results\":{},\"timings\":{},\"stage\":{}}}",
+            entry.body,
+            serde_json::Value::Object(timings),
+            stage
+        ))
+}
+
+/// Which request headers a results response depended on, for `Vary`.
+/// `Accept-Encoding` and `Origin` are added by the middleware that acts on them.
+struct Vary {
+    /// The format was negotiated rather than given as a parameter.
+    accept: bool,
+    /// Ordering by post count, where `Accept-Language` breaks ties.
+    accept_language: bool,
+}
+
+impl Vary {
+    fn header_value(&self) -> Option<&'static str> {
+        match (self.accept, self.accept_language) {
+            (true, true) => Some("Accept, Accept-Language"),
+            (true, false) => Some("Accept"),
+            (false, true) => Some("Accept-Language"),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Splits a leading `artist:`-style qualifier off a prefix. Anything before the
+/// colon that isn't a category name is left alone as part of the prefix.
+fn split_category_qualifier(prefix: &str) -> (Option<i16>, &str) {
+    match prefix.split_once(':') {
+        Some((qualifier, rest)) => match models::category_id(qualifier.trim()) {
+            Some(category) => (Some(category), rest),
+            None => (None, prefix),
+        },
+        None => (None, prefix),
+    }
+}
+
+/// The format asked for through `Accept`, for clients that don't pass `format`.
+fn negotiate_format(req: &HttpRequest) -> Format {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    let accepts = |media_type: &str| {
+        accept
+            .split(',')
+            .any(|x| x.split(';').next().unwrap_or_default().trim() == media_type)
+    };
+    if accepts("application/x-ndjson") {
+        Format::Ndjson
+    } else {
+        Format::Json
+    }
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using the weak comparison
+/// RFC 9110 asks for.
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| {
+            x.split(',')
+                .map(|x| x.trim())
+                .any(|x| x == "*" || x.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+        })
+}
+
+/// Whether an `If-Modified-Since` header is at or after `modified`. Only
+/// consulted without `If-None-Match`, which is the more precise of the two.
+fn unmodified_since(req: &HttpRequest, modified: SystemTime) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+    req.get_header::<header::IfModifiedSince>()
+        .is_some_and(|x| SystemTime::from(x.0) >= modified)
+}
+
+/// Builds the response for a cached result set, wrapping it as JSONP when a
+/// callback was given. The cache always holds plain JSON, so this happens per
+/// response.
+fn results_response(
+    data: &AutocompleteState,
+    req: &HttpRequest,
+    entry: CacheEntry,
+    format: Format,
+    callback: Option<&str>,
+    vary: Vary,
+) -> HttpResponse {
+    let no_content = data.empty_as_204 && entry.count == 0;
+    let etag = entry.etag(callback);
+    let not_modified =
+        !no_content && (etag_matches(req, &etag) || unmodified_since(req, entry.modified));
+    let mut res = if no_content {
+        HttpResponse::NoContent()
+    } else if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    // a miss can turn into a hit once the tag exists, so don't let clients
+    // hold on to it any longer than we do
+    res.insert_header((
+        header::CACHE_CONTROL,
+        match entry.count {
+            0 => data.empty_cache_control.as_str(),
+            _ => data.cache_control.as_str(),
+        },
+    ));
+    if !no_content {
+        res.insert_header((header::ETAG, etag));
+        res.insert_header(header::LastModified(entry.modified.into()));
+    }
+    if let Some(vary) = vary.header_value() {
+        res.append_header((header::VARY, vary));
+    }
+    if let Some(total) = entry.total {
+        res.insert_header(("X-Total-Count", total.to_string()));
+    }
+    if data.match_stage_header {
+        let stage = entry.stage.map_or("none", db::Stage::as_str);
+        res.insert_header(("X-Match-Stage", stage));
+    }
+    if no_content || not_modified {
+        return res.finish();
+    }
+    if let Some(callback) = callback {
+        return res
+            .insert_header((
+                header::CONTENT_TYPE,
+                "application/javascript; charset=utf-8",
+            ))
+            .body(format!("{callback}({})", entry.body));
+    }
+    res.insert_header((header::CONTENT_TYPE, format.content_type()))
+        .body(entry.body)
+}
+
+/// Sends results as they're serialized, a JSON array element or ndjson line
+/// at a time, rather than building the whole body first.
+fn streamed_response(
+    data: &AutocompleteState,
+    search: TagSearch,
+    matches: db::Matches,
+    vary: Vary,
+) -> HttpResponse {
+    let mut res = HttpResponse::Ok();
+    res.insert_header((header::CONTENT_TYPE, search.format.content_type()))
+        .insert_header((
+            header::CACHE_CONTROL,
+            match matches.tags.len() {
+                0 => data.empty_cache_control.as_str(),
+                _ => data.cache_control.as_str(),
+            },
+        ));
+    if let Some(vary) = vary.header_value() {
+        res.append_header((header::VARY, vary));
+    }
+    if let Some(total) = matches.total {
+        res.insert_header(("X-Total-Count", total.to_string()));
+    }
+    if data.match_stage_header {
+        let stage = matches.stage.map_or("none", db::Stage::as_str);
+        res.insert_header(("X-Match-Stage", stage));
+    }
+    let (open, close) = match search.format {
+        Format::Ndjson => ("", ""),
+        _ => ("[", "]"),
+    };
+    let format = search.format;
+    let results = matches.tags.into_iter().enumerate().map(move |(i, tag)| {
+        let result = serialize_result(&search, &tag);
+        match format {
+            Format::Ndjson => result + "\n",
+            _ if i == 0 => result,
+            _ => format!(",{result}"),
+        }
+    });
+    let chunks = std::iter::once(open.to_owned())
+        .chain(results)
+        .chain(std::iter::once(close.to_owned()))
+        .filter(|x| !x.is_empty())
+        .map(|x| Ok::<_, std::convert::Infallible>(web::Bytes::from(x)));
+    res.streaming(futures_util::stream::iter(chunks))
+}
+
+const MAX_BATCH_SIZE: usize = 20;
+
+#[post("/batch")]
+async fn batch(
+    data: web::Data<AutocompleteState>,
+    prefixes: web::Json<Vec<String>>,
+) -> Result<HttpResponse, AutocompleteError> {
+    if prefixes.len() > MAX_BATCH_SIZE {
+        return Err(AutocompleteError::BadRequest);
+    }
+    // validate everything up front so a bad entry doesn't cost any queries
+    let prefixes = prefixes
+        .iter()
+        .map(|x| match validate_transform_tag(&data, x) {
+            Ok(prefix) => Ok((x, Some(prefix))),
+            Err(AutocompleteError::PrefixTooShort) if data.short_prefix_empty => Ok((x, None)),
+            Err(x) => Err(x),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut seen = HashSet::new();
+    let mut body = String::from("{");
+    for (input, prefix) in prefixes {
+        if !seen.insert(input) {
+            continue;
+        }
+        let serialized = match prefix {
+            Some(prefix) => lookup(&data, &data.search(prefix), None).await?.0.body,
+            None => "[]".to_string(),
+        };
+        if body.len() > 1 {
+            body.push(',');
+        }
+        body.push_str(&serde_json::to_string(input).unwrap_or_default());
+        body.push(':');
+        body.push_str(&serialized);
+    }
+    body.push('}');
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, "private; max-age=0"))
+        .body(body))
+}
+
+/// Pre-populates the cache with short prefixes of the most used tags, so the
+/// first requests after a deploy don't all go to the database.
+async fn warm_cache(
+    data: &Data<AutocompleteState>,
+    top_n: i64,
+) -> Result<usize, AutocompleteError> {
+    let names = {
+        let client = data.client().await?;
+        db::get_top_tag_names(&client, top_n)
+            .await
+            .map_err(query_error)?
+    };
+    // the shortest prefixes anyone can search for, which are also the ones
+    // matching the most tags and so the slowest to query
+    let prefixes = names
+        .iter()
+        .map(|name| {
+            name.chars()
+                .take(*data.prefix_len.start())
+                .collect::<String>()
+        })
+        .filter_map(|x| validate_transform_tag(data, &x).ok())
+        .collect::<std::collections::BTreeSet<_>>();
+    for prefix in &prefixes {
+        lookup(data, &data.search(prefix.clone()), None).await?;
+    }
+    Ok(prefixes.len())
+}
+
+#[get("/tags/{name}")]
+async fn tag_by_name(
+    data: web::Data<AutocompleteState>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, AutocompleteError> {
+    // the same bounds as prefixes, since no longer name could be completed
+    let max_len = *data.prefix_len.end();
+    if name.chars().take(max_len * 4 + 1).count() > max_len * 4 {
+        return Err(AutocompleteError::BadRequest);
+    }
+    let name = normalize(&data, &name);
+    if name.is_empty() || name.chars().count() > max_len {
+        return Err(AutocompleteError::BadRequest);
+    }
+    let body = match data.tag_cache.get(&name).await {
+        Some(x) => x,
+        None => {
+            let client = data.client().await?;
+            let tag = db::get_tag(&client, &name)
+                .await
+                .map_err(query_error)?
+                .filter(|x| data.strategy.allows(x.category))
+                .ok_or(AutocompleteError::NotFound)?;
+            let body = serde_json::to_string(&tag).unwrap_or_default();
+            data.tag_cache.insert(name, body.clone()).await;
+            body
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, data.cache_control.as_str()))
+        .body(body))
+}
+
+#[derive(Serialize)]
+struct CacheStats {
+    entry_count: u64,
+    weighted_size: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[get("/cache/stats")]
+async fn cache_stats(data: web::Data<AutocompleteState>) -> HttpResponse {
+    let size = data.cache.size().await;
+    let stats = CacheStats {
+        entry_count: size.entries,
+        weighted_size: size.weight,
+        hits: data.metrics.cache_hits.get(),
+        misses: data.metrics.cache_misses.get(),
+        evictions: data.metrics.cache_evictions.get(),
+    };
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(serde_json::to_string(&stats).unwrap_or_else(|_| "{}".to_string()))
+}
+
+/// Checks the request's `Authorization: Bearer` token against the configured
+/// admin token. Admin endpoints are unreachable when no token is configured.
+fn authorize(data: &AutocompleteState, req: &HttpRequest) -> Result<(), AutocompleteError> {
+    let expected = data
+        .admin_token
+        .as_deref()
+        .ok_or(AutocompleteError::Unauthorized)?;
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .ok_or(AutocompleteError::Unauthorized)?;
+    // compare without short-circuiting so timing doesn't leak the token
+    let matches = given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0;
+    if !matches {
+        return Err(AutocompleteError::Unauthorized);
+    }
+    Ok(())
+}
+
+#[delete("/cache")]
+async fn purge_cache(
+    data: web::Data<AutocompleteState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AutocompleteError> {
+    authorize(&data, &req)?;
+    data.cache.invalidate_all().await;
+    data.tag_cache.invalidate_all();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/cache/{prefix}")]
+async fn purge_prefix(
+    data: web::Data<AutocompleteState>,
+    req: HttpRequest,
+    prefix: web::Path<String>,
+) -> Result<HttpResponse, AutocompleteError> {
+    authorize(&data, &req)?;
+    let prefix = validate_transform_tag(&data, &prefix)?;
+    // every search option is part of the key, so drop all entries for the prefix
+    if let Err(x) = data.cache.invalidate_prefix(&prefix).await {
+        error!("{}", x);
+        return Err(AutocompleteError::ServerError);
+    }
+    // and the tag by that name, which is edited along with the results
+    data.tag_cache.invalidate(&prefix).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/openapi.json")]
+async fn openapi_document(data: web::Data<AutocompleteState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .body(data.openapi.clone())
+}
+
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body("{\"status\":\"ok\"}")
+}
+
+#[get("/readyz")]
+async fn readyz(data: web::Data<AutocompleteState>) -> Result<HttpResponse, AutocompleteError> {
+    use actix_web::rt::time::timeout;
+
+    let check = async {
+        let client = data.client().await.map_err(|x| x.to_string())?;
+        client
+            .execute("SELECT 1", &[])
+            .await
+            .map_err(|x| x.to_string())?;
+        Ok::<(), String>(())
+    };
+    match timeout(Duration::from_secs(2), check).await {
+        Ok(Ok(())) => Ok(HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "application/json; charset=utf-8"))
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .body("{\"status\":\"ok\"}")),
+        Ok(Err(x)) => {
+            error!("readiness check failed: {}", x);
+            Err(AutocompleteError::ServiceUnavailable)
+        }
+        Err(_) => {
+            error!("readiness check timed out");
+            Err(AutocompleteError::ServiceUnavailable)
+        }
+    }
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint(
+    data: web::Data<AutocompleteState>,
+) -> Result<HttpResponse, AutocompleteError> {
+    use prometheus::{Encoder, TextEncoder};
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(x) = encoder.encode(&data.metrics.registry.gather(), &mut buffer) {
+        error!("{}", x);
+        return Err(AutocompleteError::ServerError);
+    }
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, encoder.format_type()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(buffer))
+}
+
+const MAX_STARTUP_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Waits for the database to answer `SELECT 1`, retrying with exponential
+/// backoff, so we don't start taking requests we can't serve.
+async fn wait_for_db(pool: &Pool, attempts: u32, base_delay: Duration) -> Result<(), String> {
+    let mut delay = base_delay;
+    for attempt in 1..=attempts {
+        let check = async {
+            let client = pool.get().await.map_err(|x| x.to_string())?;
+            client
+                .execute("SELECT 1", &[])
+                .await
+                .map_err(|x| x.to_string())?;
+            Ok::<(), String>(())
+        };
+        match check.await {
+            Ok(()) => return Ok(()),
+            Err(x) if attempt == attempts => return Err(x),
+            Err(x) => {
+                warn!(
+                    "database unreachable (attempt {}/{}), retrying in {:?}: {}",
+                    attempt, attempts, delay, x
+                );
+                actix_web::rt::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_STARTUP_RETRY_DELAY);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counts a request as in flight for as long as it's held, however it ends.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Admits a request unless `max` are already in flight.
+    fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        let previous = count.fetch_add(1, Ordering::AcqRel);
+        // dropped straight away when turned down, which undoes the increment
+        let guard = InFlight(count.clone());
+        (previous < max).then_some(guard)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Identifies a request across log lines; taken from the client's
+/// `X-Request-Id` when it sends a usable one.
+#[derive(Clone)]
+struct RequestId(String);
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+impl RequestId {
+    fn for_request(req: &ServiceRequest) -> Self {
+        let supplied = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| !x.is_empty() && x.len() <= MAX_REQUEST_ID_LEN);
+        RequestId(match supplied {
+            Some(x) => x.to_owned(),
+            None => uuid::Uuid::new_v4().to_string(),
+        })
+    }
+}
+
+/// An address range like `10.0.0.0/8`; a bare address is a range of one.
+#[derive(Clone, Copy)]
+struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {s:?}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|x| *x <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+            None => max_len,
+        };
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The client's address. `X-Forwarded-For` is only believed as far back as it
+/// was added by trusted proxies, since anyone else can put anything in it.
+fn client_ip(req: &HttpRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|x| x.ip())?;
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|x| x.contains(ip));
+    if !trusted(peer) {
+        return Some(peer);
+    }
+    let forwarded = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    // walk back from the nearest hop to the first address we didn't add ourselves
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !trusted(ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+/// What the middleware in front of the handlers needs, shared by every
+/// worker's [`app`].
+#[derive(Clone)]
+struct HttpSettings {
+    allowed_origins: Vec<String>,
+    security_headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    rate_limiter: Option<Arc<ratelimit::RateLimiter>>,
+    trusted_proxies: Vec<Cidr>,
+    max_in_flight: usize,
+    /// Requests being handled right now, across all workers.
+    in_flight: Arc<AtomicUsize>,
+    base_path: String,
+    probes_at_root: bool,
+}
+
+impl HttpSettings {
+    fn new(config: &config::Config, state: &AutocompleteState) -> Self {
+        let security_headers = [
+            (
+                header::CONTENT_SECURITY_POLICY,
+                &config.content_security_policy,
+                "CONTENT_SECURITY_POLICY",
+            ),
+            (
+                header::REFERRER_POLICY,
+                &config.referrer_policy,
+                "REFERRER_POLICY",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value, var)| {
+            let value = header::HeaderValue::from_str(value.as_deref()?)
+                .unwrap_or_else(|_| panic!("Failed to parse {var}"));
+            Some((name, value))
+        })
+        .collect();
+        HttpSettings {
+            allowed_origins: config.allowed_origins.clone(),
+            security_headers,
+            rate_limiter: (config.rate_limit_per_sec > 0.0).then(|| {
+                Arc::new(ratelimit::RateLimiter::new(
+                    config.rate_limit_per_sec,
+                    config.rate_limit_burst,
+                ))
+            }),
+            trusted_proxies: state.trusted_proxies.clone(),
+            max_in_flight: config.max_in_flight_requests,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            base_path: config.base_path(),
+            probes_at_root: config.probes_at_root,
+        }
+    }
+}
+
+/// The whole service: middleware, probes and the routes under the base path.
+fn app(
+    state: Data<AutocompleteState>,
+    http: &HttpSettings,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let HttpSettings {
+        allowed_origins,
+        security_headers,
+        rate_limiter,
+        trusted_proxies,
+        max_in_flight,
+        in_flight,
+        base_path,
+        probes_at_root,
+    } = http.clone();
+    let metrics = state.metrics.clone();
+    let mut default_headers = DefaultHeaders::new()
+        // keeps a JSON body from ever being sniffed as HTML
+        .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .add((header::ACCESS_CONTROL_ALLOW_HEADERS, "Authorization"))
+        .add((
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            "X-Total-Count, X-Request-Id, ETag, X-Match-Stage",
+        ));
+    for header in &security_headers {
+        default_headers = default_headers.add(header.clone());
+    }
+    if allowed_origins.is_empty() {
+        default_headers = default_headers.add((header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"));
+    }
+    App::new()
+        .wrap(Compress::default())
+        .wrap(default_headers)
+        .wrap_fn(move |req, srv| {
+            // with an allowlist, only echo the origin back when it's on the list
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .filter(|x| allowed_origins.iter().any(|o| o.as_bytes() == x.as_bytes()))
+                .cloned();
+            let restricted = !allowed_origins.is_empty();
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                if let Some(origin) = origin {
+                    res.headers_mut()
+                        .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                }
+                if restricted {
+                    res.headers_mut()
+                        .append(header::VARY, header::HeaderValue::from_static("Origin"));
+                }
+                Ok(res)
+            }
+        })
+        .wrap_fn(|req, srv| {
+            let request_id = RequestId::for_request(&req);
+            let span = tracing::debug_span!("request", request_id = %request_id.0);
+            let header_value = header::HeaderValue::from_str(&request_id.0).ok();
+            req.extensions_mut().insert(request_id);
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                if let Some(value) = header_value {
+                    res.headers_mut()
+                        .insert(header::HeaderName::from_static("x-request-id"), value);
+                }
+                Ok(res)
+            }
+            .instrument(span)
+        })
+        .app_data(state.clone())
+        // answer malformed or missing parameters with our usual JSON error
+        // instead of the extractors' plain text ones
+        .app_data(web::QueryConfig::default().error_handler(extractor_error))
+        .app_data(web::JsonConfig::default().error_handler(extractor_error))
+        // orchestrators often probe fixed paths, whatever the base path is
+        .configure(|cfg| {
+            if probes_at_root {
+                probes(cfg);
+            }
+        })
+        .service(web::scope(&base_path).configure(move |cfg| {
+            // ahead of the scope below, which takes everything else
+            if !probes_at_root {
+                probes(cfg);
+            }
+            cfg.service(
+                web::scope("")
+                    // turn excess requests away before they reach a handler,
+                    // rather than let every request slow down under load;
+                    // probes are left alone, since a busy or throttled
+                    // instance isn't a dead one
+                    .wrap_fn(move |req, srv| {
+                        let admitted = (max_in_flight > 0)
+                            .then(|| InFlight::acquire(&in_flight, max_in_flight));
+                        let fut = match admitted {
+                            Some(None) => {
+                                metrics.requests_shed.inc();
+                                Err(req.error_response(AutocompleteError::Overloaded))
+                            }
+                            admitted => Ok((srv.call(req), admitted)),
+                        };
+                        async move {
+                            match fut {
+                                Ok((fut, admitted)) => {
+                                    let res = fut.await;
+                                    drop(admitted);
+                                    res.map(ServiceResponse::map_into_boxed_body)
+                                }
+                                Err(res) => Ok(res),
+                            }
+                        }
+                    })
+                    .wrap_fn(move |req, srv| {
+                        let throttled = rate_limiter
+                            .as_ref()
+                            .zip(client_ip(req.request(), &trusted_proxies))
+                            .and_then(|(limiter, ip)| limiter.check(ip).err());
+                        let fut = match throttled {
+                            None => Ok(srv.call(req)),
+                            Some(wait) => {
+                                let retry_after = wait.as_secs_f64().ceil() as u64;
+                                let error = AutocompleteError::TooManyRequests(retry_after);
+                                Err(req.error_response(error))
+                            }
+                        };
+                        async move {
+                            match fut {
+                                Ok(fut) => fut.await.map(ServiceResponse::map_into_boxed_body),
+                                Err(res) => Ok(res),
+                            }
+                        }
+                    })
+                    .service(autocomplete)
+                    .service(autocomplete_json)
+                    .service(batch)
+                    .service(tag_by_name)
+                    .service(openapi_document)
+                    .service(cache_stats)
+                    .service(purge_cache)
+                    .service(purge_prefix),
+            );
+        }))
+}
+
+/// The health and metrics endpoints.
+fn probes(cfg: &mut web::ServiceConfig) {
+    cfg.service(healthz)
+        .service(readyz)
+        .service(metrics_endpoint);
+}
+
+/// The lifetime totals logged at shutdown.
+struct ShutdownSummary {
+    requests: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    cache_hit_ratio: f64,
+    cache_evictions: u64,
+    requests_shed: u64,
+}
+
+impl ShutdownSummary {
+    fn new(metrics: &metrics::Metrics) -> Self {
+        let (hits, misses) = (metrics.cache_hits.get(), metrics.cache_misses.get());
+        let lookups = hits + misses;
+        ShutdownSummary {
+            requests: metrics.requests.get(),
+            cache_hits: hits,
+            cache_misses: misses,
+            cache_hit_ratio: if lookups > 0 {
+                hits as f64 / lookups as f64
+            } else {
+                0.0
+            },
+            cache_evictions: metrics.cache_evictions.get(),
+            requests_shed: metrics.requests_shed.get(),
+        }
+    }
+}
+
+impl log::kv::Source for ShutdownSummary {
+    fn visit<'kvs>(
+        &'kvs self,
+        visitor: &mut dyn log::kv::VisitSource<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        use log::kv::{Key, Value};
+        visitor.visit_pair(Key::from("requests"), Value::from(self.requests))?;
+        visitor.visit_pair(Key::from("cache_hits"), Value::from(self.cache_hits))?;
+        visitor.visit_pair(Key::from("cache_misses"), Value::from(self.cache_misses))?;
+        visitor.visit_pair(
+            Key::from("cache_hit_ratio"),
+            Value::from(self.cache_hit_ratio),
+        )?;
+        visitor.visit_pair(
+            Key::from("cache_evictions"),
+            Value::from(self.cache_evictions),
+        )?;
+        visitor.visit_pair(Key::from("requests_shed"), Value::from(self.requests_shed))
+    }
+}
+
+/// Applies the settings that go into `config.pg`, and from there into every
+/// connection to the primary and replicas: the pool size, the statement
+/// timeout and the application name.
+fn configure_connections(config: &mut config::Config) {
+    if let Some(max_size) = config.pg_pool_max_size {
+        let mut pool = config.pg.get_pool_config();
+        pool.max_size = max_size;
+        config.pg.pool = Some(pool);
+    }
+    // applied once per connection; it's numeric, so formatting it in is safe
+    let statement_timeout = format!("-c statement_timeout={}", config.statement_timeout_ms);
+    config.pg.options = Some(match config.pg.options.as_deref() {
+        Some(existing) => format!("{existing} {statement_timeout}"),
+        None => statement_timeout,
+    });
+    // a startup parameter too, so it's sent once per connection and shows up
+    // in pg_stat_activity; PG__APPLICATION_NAME still wins if given
+    if config.pg.application_name.is_none() {
+        config.pg.application_name = Some(config.application_name.clone());
+    }
+}
+
+/// Checks the connection pool's size against the workers and
+/// `MAX_CONCURRENT_QUERIES`, returning what's worth warning about, or why
+/// the pool can't work at all.
+fn validate_pool(config: &config::Config) -> Result<Vec<String>, String> {
+    let pool_size = config
+        .pg_pool_max_size
+        .unwrap_or_else(|| config.pg.get_pool_config().max_size);
+    if pool_size == 0 {
+        return Err("PG_POOL_MAX_SIZE must be at least 1".to_owned());
+    }
+    // actix's default, one per CPU
+    let workers = config
+        .workers
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+    let mut warnings = Vec::new();
+    if pool_size < workers {
+        warnings.push(format!(
+            "the connection pool's {pool_size} connections are fewer than the {workers} workers, which will wait on each other for them"
+        ));
+    }
+    if config.max_concurrent_queries > pool_size {
+        warnings.push(format!(
+            "MAX_CONCURRENT_QUERIES ({}) is more than the pool's {pool_size} connections, so the pool limits queries first",
+            config.max_concurrent_queries
+        ));
+    }
+    Ok(warnings)
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    use actix_web::HttpServer;
+    use tokio_postgres::NoTls;
+    let config =
+        crate::config::Config::from_env().expect("Failed to load configuration from environment");
+    logging::init(config.log_format);
+
+    assert!(
+        (1..=config.max_prefix_len).contains(&config.min_prefix_len),
+        "MIN_PREFIX_LEN must be between 1 and MAX_PREFIX_LEN"
+    );
+
+    let mut config = config;
+    configure_connections(&mut config);
+    match validate_pool(&config) {
+        Ok(warnings) => warnings.iter().for_each(|x| warn!("{}", x)),
+        Err(x) => panic!("{x}"),
+    }
+    let tls = config.pg_tls.then(|| {
+        tls::postgres_connector(config.pg_tls_ca_path.as_deref())
+            .expect("Failed to set up PostgreSQL TLS")
+    });
+    let create_pool = |pg: &deadpool_postgres::Config| match &tls {
+        Some(tls) => pg.create_pool(Some(Runtime::Tokio1), tls.clone()),
+        None => pg.create_pool(Some(Runtime::Tokio1), NoTls),
+    };
+    let pool = create_pool(&config.pg).expect("Failed to create PostgreSQL connection pool");
+    let replicas = config
+        .replica_configs()
+        .iter()
+        .map(create_pool)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to create PostgreSQL replica connection pool");
+    if let Err(x) = wait_for_db(
+        &pool,
+        config.startup_db_attempts,
+        Duration::from_millis(config.startup_db_retry_ms),
+    )
+    .await
+    {
+        error!("database unreachable, giving up: {}", x);
+        return Err(std::io::Error::other(x));
+    }
+    let queries = match db::Queries::load(
+        config.sql_fetch_a_path.as_deref(),
+        config.sql_fetch_b_path.as_deref(),
+        config.prefix_table.as_deref(),
+    ) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("{}", x);
+            return Err(std::io::Error::other(x));
+        }
+    };
+    // only once we know the database is there to ask
+    if config.startup_db_attempts > 0 {
+        let check = async {
+            let client = pool.get().await.map_err(|x| x.to_string())?;
+            db::check_schema(&client, &queries).await
+        };
+        if let Err(x) = check.await {
+            error!("database schema check failed: {}", x);
+            return Err(std::io::Error::other(x));
+        }
+    }
+    let state = Data::new(AutocompleteState::new(&config, pool, replicas, queries));
+    if config.warm_cache {
+        match warm_cache(&state, config.warm_cache_top_n).await {
+            Ok(x) => info!("warmed cache with {} prefixes", x),
+            Err(x) => error!("failed to warm cache: {}", x),
+        }
+    }
+
+    let server_tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => {
+            Some(tls::server_config(cert, key).expect("Failed to load TLS certificate"))
+        }
+        (None, None) => None,
+        _ => panic!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+    };
+
+    let server_state = state.clone();
+    let http = HttpSettings::new(&config, &state);
+
+    let server = HttpServer::new(move || app(state.clone(), &http))
+        // actix stops gracefully on SIGTERM/SIGINT, draining in-flight requests for
+        // up to this long before dropping them
+        .shutdown_timeout(config.shutdown_timeout_secs);
+    // actix defaults to one worker per CPU
+    let server = match config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match server_tls {
+        Some(tls) => server.bind_rustls_0_23(config.server_addr.clone(), tls)?,
+        None => server.bind(config.server_addr.clone())?,
+    };
+    server.run().await?;
+
+    // once, after the last request has been answered, for whoever reads the
+    // logs after the container is gone
+    let summary = ShutdownSummary::new(&server_state.metrics);
+    logging::info(module_path!(), "shutting down", &summary);
+    server_state.pool.close();
+    for replica in &server_state.replicas {
+        replica.close();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `vars` on top of the minimum configuration.
+    fn configured(vars: &[(&str, &str)]) -> config::Config {
+        let mut all = vec![("SERVER_ADDR", "127.0.0.1:0"), ("PG__DBNAME", "unused")];
+        all.extend_from_slice(vars);
+        config::Config::from_vars(&all)
+    }
+
+    /// State for `config`, with a pool that never gets to connect.
+    fn state_for(config: &config::Config) -> AutocompleteState {
+        let pool = config
+            .pg
+            .create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)
+            .unwrap();
+        state_with_pool(config, pool)
+    }
+
+    fn state_with_pool(config: &config::Config, pool: Pool) -> AutocompleteState {
+        let queries = db::Queries::load(None, None, None).unwrap();
+        AutocompleteState::new(config, pool, Vec::new(), queries)
+    }
+
+    fn state(vars: &[(&str, &str)]) -> AutocompleteState {
+        state_for(&configured(vars))
+    }
+
+    /// A backend that keeps everything, for checking handlers only go through
+    /// [`CacheBackend`].
+    #[derive(Default)]
+    struct MockCache(Mutex<std::collections::HashMap<TagSearch, CacheEntry>>);
+
+    #[async_trait]
+    impl CacheBackend for MockCache {
+        async fn get(&self, key: &TagSearch) -> Option<CacheEntry> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+
+        async fn insert(&self, key: TagSearch, entry: CacheEntry) {
+            self.0.lock().unwrap().insert(key, entry);
+        }
+
+        async fn invalidate(&self, key: &TagSearch) {
+            self.0.lock().unwrap().remove(key);
+        }
+
+        async fn invalidate_all(&self) {
+            self.0.lock().unwrap().clear();
+        }
+
+        async fn invalidate_prefix(&self, prefix: &str) -> Result<(), String> {
+            self.0.lock().unwrap().retain(|key, _| key.prefix != prefix);
+            Ok(())
+        }
+
+        async fn size(&self) -> CacheSize {
+            let entries = self.0.lock().unwrap();
+            CacheSize {
+                entries: entries.len() as u64,
+                weight: entries.values().map(|x| x.body.len() as u64).sum(),
+            }
+        }
+    }
+
+    /// What [`app`] takes, for `vars`.
+    fn server(vars: &[(&str, &str)]) -> (Data<AutocompleteState>, HttpSettings) {
+        let config = configured(vars);
+        let state = state_for(&config);
+        let http = HttpSettings::new(&config, &state);
+        (Data::new(state), http)
+    }
+
+    /// [`server`], caching in a [`MockCache`] so that handlers can be run
+    /// against [`cached`] entries without a database.
+    fn mocked(vars: &[(&str, &str)]) -> (Data<AutocompleteState>, HttpSettings) {
+        let config = configured(vars);
+        let state = AutocompleteState {
+            cache: Box::new(MockCache::default()),
+            ..state_for(&config)
+        };
+        let http = HttpSettings::new(&config, &state);
+        (Data::new(state), http)
+    }
+
+    /// Caches `body` with `count` results for `search`.
+    async fn cached(data: &AutocompleteState, search: TagSearch, body: &str, count: usize) {
+        let entry = CacheEntry::new(body.to_owned(), count, None);
+        data.cache.insert(search, entry).await;
+    }
+
+    /// Caches `tags` for `search` serialized as a query finding them would be,
+    /// so response shapes can be checked without a database.
+    async fn found(data: &AutocompleteState, search: TagSearch, tags: &[Tag]) {
+        let body = serialize_results(&search, tags);
+        cached(data, search, &body, tags.len()).await;
+    }
+
+    /// What a search for "fur" might find: two direct matches and an alias.
+    fn fur_results() -> Vec<Tag> {
+        let tag = |id, name: &str, post_count, category, antecedent_name: Option<&str>| Tag {
+            id,
+            name: name.to_owned(),
+            post_count,
+            category,
+            antecedent_name: antecedent_name.map(str::to_owned),
+        };
+        vec![
+            tag(1, "furry", 5000, 0, None),
+            tag(2, "fox", 2000, 5, Some("furred_fox")),
+            tag(3, "fur", 100, 0, None),
+        ]
+    }
+
+    #[test]
+    fn prefix_length_counts_characters() {
+        let data = state(&[]);
+        assert_eq!(validate_transform_tag(&data, "abc").unwrap(), "abc");
+        assert!(matches!(
+            validate_transform_tag(&data, "ab"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+        // three characters in nine bytes, and two in six
+        assert_eq!(validate_transform_tag(&data, "日本語").unwrap(), "日本語");
+        assert!(matches!(
+            validate_transform_tag(&data, "日本"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+        assert!(validate_transform_tag(&data, &"猫".repeat(100)).is_ok());
+        assert!(matches!(
+            validate_transform_tag(&data, &"猫".repeat(101)),
+            Err(AutocompleteError::PrefixTooLong)
+        ));
+    }
+
+    #[test]
+    fn prefix_length_is_measured_after_composition() {
+        let data = state(&[]);
+        assert_eq!(
+            validate_transform_tag(&data, "e\u{301}ab").unwrap(),
+            "\u{e9}ab"
+        );
+        assert!(matches!(
+            validate_transform_tag(&data, "e\u{301}a"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+        // wildcards don't count towards the minimum
+        assert!(matches!(
+            validate_transform_tag(&data, "ab*"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+    }
+
+    #[test]
+    fn prefix_length_follows_the_configured_bounds() {
+        let data = state(&[("MIN_PREFIX_LEN", "2"), ("MAX_PREFIX_LEN", "5")]);
+        assert!(matches!(
+            validate_transform_tag(&data, "a"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+        assert_eq!(validate_transform_tag(&data, "ab").unwrap(), "ab");
+        assert_eq!(validate_transform_tag(&data, "abcde").unwrap(), "abcde");
+        assert!(matches!(
+            validate_transform_tag(&data, "abcdef"),
+            Err(AutocompleteError::PrefixTooLong)
+        ));
+        // the bounds apply to what normalize leaves
+        assert_eq!(normalize(&data, "a b c d e"), "abcde");
+        assert_eq!(validate_transform_tag(&data, "a b c d e").unwrap(), "abcde");
+        assert!(matches!(
+            validate_transform_tag(&data, "a b c d e f"),
+            Err(AutocompleteError::PrefixTooLong)
+        ));
+        // but raw input over four times the maximum is refused before that
+        let padded = |n| "\u{200b}".repeat(n) + "ab";
+        assert_eq!(validate_transform_tag(&data, &padded(18)).unwrap(), "ab");
+        assert!(matches!(
+            validate_transform_tag(&data, &padded(19)),
+            Err(AutocompleteError::PrefixTooLong)
+        ));
+    }
+
+    #[test]
+    fn separators_become_the_configured_one() {
+        let data = state(&[("WORD_SEPARATOR", "_")]);
+        assert_eq!(normalize(&data, "long hair"), "long_hair");
+        assert_eq!(normalize(&data, "long-hair"), "long_hair");
+        assert_eq!(normalize(&data, " long\thair-style "), "long_hair_style");
+        assert_eq!(normalize(&data, "long_hair"), "long_hair");
+        // without one, whitespace is dropped and hyphens stay
+        let data = state(&[]);
+        assert_eq!(normalize(&data, "long hair"), "longhair");
+        assert_eq!(normalize(&data, "long-hair"), "long-hair");
+    }
+
+    #[test]
+    fn invisible_characters_are_dropped() {
+        let data = state(&[]);
+        assert_eq!(normalize(&data, "fu\u{200b}r"), "fur");
+        assert_eq!(normalize(&data, "\u{feff}fur"), "fur");
+        assert_eq!(normalize(&data, "fur\u{200d}ry\u{7}"), "furry");
+        // dropped before composing, so what they separated still composes
+        assert_eq!(normalize(&data, "e\u{200b}\u{301}"), "\u{e9}");
+        assert!(matches!(
+            validate_transform_tag(&data, "\u{200b}\u{200d}\u{feff}ab"),
+            Err(AutocompleteError::PrefixTooShort)
+        ));
+    }
+
+    #[test]
+    fn ascii_lowercasing_keeps_dotted_i_plain() {
+        let unicode = state(&[]);
+        let ascii = state(&[("LOWERCASE", "ascii")]);
+        // Unicode lowercases İ to i and a combining dot
+        assert_eq!(normalize(&unicode, "İstanbul"), "i\u{307}stanbul");
+        assert_eq!(normalize(&ascii, "İstanbul"), "istanbul");
+        assert_eq!(normalize(&unicode, "FUR"), normalize(&ascii, "FUR"));
+        // other scripts are lowercased the same either way
+        assert_eq!(normalize(&unicode, "ÄΣЖ"), "äσж");
+        assert_eq!(normalize(&ascii, "ÄΣЖ"), "äσж");
+        assert_eq!(normalize(&ascii, "ı"), "ı");
+    }
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let [net, host, v6] = &cidrs(&["10.0.0.0/8", "192.0.2.1", "2001:db8::/32"])[..] else {
+            unreachable!()
+        };
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(host.contains("192.0.2.1".parse().unwrap()));
+        assert!(!host.contains("192.0.2.2".parse().unwrap()));
+        assert!(v6.contains("2001:db8::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+        // IPv4-mapped IPv6 peers are compared as IPv4
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(cidrs(&["0.0.0.0/0"])[0].contains("203.0.113.9".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    fn forwarded(peer: &str, header: &str) -> HttpRequest {
+        actix_web::test::TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", header))
+            .to_http_request()
+    }
+
+    #[test]
+    fn client_ip_ignores_forged_forwarded_for() {
+        let proxies = cidrs(&["10.0.0.0/8"]);
+        let ip = |x: &str| Some(x.parse::<IpAddr>().unwrap());
+        // straight from the client, whatever it claims
+        let req = forwarded("203.0.113.9:1234", "198.51.100.1");
+        assert_eq!(client_ip(&req, &proxies), ip("203.0.113.9"));
+        // through our proxy, which appended the address it saw
+        let req = forwarded("10.0.0.2:1234", "198.51.100.1, 203.0.113.9");
+        assert_eq!(client_ip(&req, &proxies), ip("203.0.113.9"));
+        // through two of ours
+        let req = forwarded("10.0.0.2:1234", "203.0.113.9, 10.0.0.3");
+        assert_eq!(client_ip(&req, &proxies), ip("203.0.113.9"));
+        // garbage the client sent stops the walk rather than being skipped
+        let req = forwarded("10.0.0.2:1234", "bogus, 10.0.0.3");
+        assert_eq!(client_ip(&req, &proxies), ip("10.0.0.3"));
+        // nothing is trusted by default
+        let req = forwarded("10.0.0.2:1234", "203.0.113.9");
+        assert_eq!(client_ip(&req, &[]), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn callbacks_must_be_identifiers() {
+        for valid in ["cb", "_cb", "$", "jQuery123_456", "a$b"] {
+            assert!(is_valid_callback(valid), "{valid:?}");
+        }
+        for invalid in [
+            "",
+            "1cb",
+            "cb()",
+            "cb;alert(1)",
+            "a.b",
+            "a-b",
+            "</script>",
+            "caf\u{e9}",
+            "cb\n",
+        ] {
+            assert!(!is_valid_callback(invalid), "{invalid:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn jsonp_wraps_valid_callbacks_and_rejects_others() {
+        use actix_web::test;
+        let (data, http) = server(&[("SHORT_PREFIX_EMPTY", "true")]);
+        let app = test::init_service(app(data, &http)).await;
+        let req = test::TestRequest::get()
+            .uri("/?search[name_matches]=ab&callback=cb")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(test::read_body(res).await, "cb([])");
+        for callback in ["alert(1)//", "cb%3Balert(1)", "%3C/script%3E"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=ab&callback={callback}"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{callback:?}");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["code"], "BAD_REQUEST");
+        }
+    }
+
+    #[actix_web::test]
+    async fn rate_limit_answers_429_with_retry_after() {
+        use actix_web::test;
+        let (data, http) = server(&[
+            ("RATE_LIMIT_PER_SEC", "1"),
+            ("RATE_LIMIT_BURST", "3"),
+            ("SHORT_PREFIX_EMPTY", "true"),
+        ]);
+        let app = test::init_service(app(data, &http)).await;
+        let get = |uri: &str, peer: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .peer_addr(peer.parse().unwrap())
+                .to_request()
+        };
+        let search = "/?search[name_matches]=ab";
+        for _ in 0..3 {
+            let res = test::call_service(&app, get(search, "203.0.113.9:1234")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = test::call_service(&app, get(search, "203.0.113.9:1234")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let res = test::call_service(&app, get(search, "203.0.113.10:1234")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // a load balancer probing from a throttled address still gets through
+        for probe in ["/healthz", "/readyz", "/metrics"] {
+            let res = test::call_service(&app, get(probe, "203.0.113.9:1234")).await;
+            assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS, "{probe}");
+        }
+    }
+
+    /// A backend whose every lookup hits, but only once it's let through, for
+    /// holding requests in flight.
+    struct Stalled(Arc<Semaphore>);
+
+    #[async_trait]
+    impl CacheBackend for Stalled {
+        async fn get(&self, _key: &TagSearch) -> Option<CacheEntry> {
+            let _ = self.0.acquire().await;
+            Some(CacheEntry::empty(Format::Json))
+        }
+
+        async fn insert(&self, _key: TagSearch, _entry: CacheEntry) {}
+
+        async fn invalidate(&self, _key: &TagSearch) {}
+
+        async fn invalidate_all(&self) {}
+
+        async fn invalidate_prefix(&self, _prefix: &str) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn size(&self) -> CacheSize {
+            CacheSize {
+                entries: 0,
+                weight: 0,
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn requests_past_max_in_flight_are_shed_but_probes_are_not() {
+        use actix_web::test;
+        let config = configured(&[("MAX_IN_FLIGHT_REQUESTS", "1")]);
+        let gate = Arc::new(Semaphore::new(0));
+        let data = Data::new(AutocompleteState {
+            cache: Box::new(Stalled(gate.clone())),
+            ..state_for(&config)
+        });
+        let http = HttpSettings::new(&config, &data);
+        let service = std::rc::Rc::new(test::init_service(app(data.clone(), &http)).await);
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+        let held = actix_web::rt::spawn({
+            let service = service.clone();
+            let req = get("/?search[name_matches]=fur");
+            async move { test::call_service(&*service, req).await.status() }
+        });
+        while http.in_flight.load(Ordering::Acquire) == 0 {
+            actix_web::rt::task::yield_now().await;
+        }
+        let res = test::call_service(&*service, get("/?search[name_matches]=fox")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "OVERLOADED");
+        assert_eq!(data.metrics.requests_shed.get(), 1);
+        for probe in ["/healthz", "/metrics"] {
+            let res = test::call_service(&*service, get(probe)).await;
+            assert_eq!(res.status(), StatusCode::OK, "{probe}");
+        }
+        // the one in flight finishes, and makes room for the next
+        gate.add_permits(1);
+        assert_eq!(held.await.unwrap(), StatusCode::OK);
+        assert_eq!(http.in_flight.load(Ordering::Acquire), 0);
+        let res = test::call_service(&*service, get("/?search[name_matches]=fox")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn category_qualifiers_are_split_off() {
+        assert_eq!(split_category_qualifier("artist:foo"), (Some(1), "foo"));
+        assert_eq!(split_category_qualifier("Species:fox"), (Some(5), "fox"));
+        assert_eq!(split_category_qualifier(" meta :foo"), (Some(7), "foo"));
+        // only the first colon qualifies
+        assert_eq!(split_category_qualifier("lore:a:b"), (Some(8), "a:b"));
+        assert_eq!(split_category_qualifier("nope:foo"), (None, "nope:foo"));
+        assert_eq!(split_category_qualifier(":foo"), (None, ":foo"));
+        assert_eq!(split_category_qualifier("foo"), (None, "foo"));
+        assert_eq!(split_category_qualifier("artist:"), (Some(1), ""));
+    }
+
+    #[test]
+    fn repeated_prefixes_are_split_out() {
+        let (prefixes, rest) = split_repeated_prefixes("search[name_matches]=fur&limit=5");
+        assert_eq!(prefixes, ["fur"]);
+        assert_eq!(rest, "limit=5");
+        let (prefixes, rest) = split_repeated_prefixes(
+            "search%5Bname_matches%5D=fur&limit=5&search[name_matches]=wolf%20pack&category=5",
+        );
+        assert_eq!(prefixes, ["fur", "wolf pack"]);
+        assert_eq!(rest, "limit=5&category=5");
+        let (prefixes, rest) = split_repeated_prefixes("q=fur&limit=5");
+        assert!(prefixes.is_empty());
+        assert_eq!(rest, "q=fur&limit=5");
+        let (prefixes, rest) = split_repeated_prefixes("");
+        assert!(prefixes.is_empty());
+        assert_eq!(rest, "");
+    }
+
+    #[actix_web::test]
+    async fn repeated_prefixes_are_nested() {
+        use actix_web::test;
+        let (data, http) = server(&[("SHORT_PREFIX_EMPTY", "true")]);
+        let app = test::init_service(app(data, &http)).await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+        let res = test::call_service(&app, get("/?search[name_matches]=ab")).await;
+        assert_eq!(test::read_body(res).await, "[]");
+        let res = test::call_service(
+            &app,
+            get("/?search[name_matches]=ab&search[name_matches]=x&search[name_matches]=ab"),
+        )
+        .await;
+        assert_eq!(test::read_body(res).await, r#"{"ab":[],"x":[]}"#);
+        let res = test::call_service(
+            &app,
+            get("/?search[name_matches]=ab&search[name_matches]=x&callback=cb"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn repeated_prefixes_are_logged_normalized() {
+        let (data, _) = mocked(&[]);
+        for prefix in ["fur", "fox"] {
+            cached(&data, data.search(prefix.to_owned()), "[]", 0).await;
+        }
+        let mut req = web::Query::<Req>::from_query("").unwrap().into_inner();
+        req.prefixes = vec![" FUR ".to_owned(), "F o X".to_owned()];
+        let http_req = actix_web::test::TestRequest::default().to_http_request();
+        let mut access = AccessLog::default();
+        let res = complete(&data, &req, &http_req, &mut access).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(access.prefix, "fur,fox");
+        assert!(access.cache_hit);
+    }
+
+    #[actix_web::test]
+    async fn healthz_answers_without_the_database() {
+        use actix_web::test;
+        let (data, http) = server(&[]);
+        let app = test::init_service(app(data, &http)).await;
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+        assert_eq!(test::read_body(res).await, r#"{"status":"ok"}"#);
+    }
+
+    #[actix_web::test]
+    async fn query_errors_are_classified_but_stay_generic() {
+        let mapping = db::DbError::Mapping(tokio_pg_mapper::Error::ColumnNotFound);
+        assert_eq!(mapping.kind(), "mapping");
+        assert!(matches!(
+            query_error(mapping),
+            AutocompleteError::ServerError
+        ));
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        let client = db.pool.get().await.unwrap();
+        let sql = db::DbError::from(client.query("SELEC 1", &[]).await.unwrap_err());
+        assert_eq!((sql.kind(), sql.sql_state()), ("sql", Some("42601")));
+        assert!(matches!(query_error(sql), AutocompleteError::ServerError));
+        client
+            .batch_execute("SET statement_timeout = 1")
+            .await
+            .unwrap();
+        let timeout = db::DbError::from(client.query("SELECT pg_sleep(1)", &[]).await.unwrap_err());
+        assert_eq!(timeout.kind(), "statement_timeout");
+        assert!(timeout.is_timeout());
+        assert!(matches!(
+            query_error(timeout),
+            AutocompleteError::GatewayTimeout
+        ));
+        let _ = client
+            .batch_execute("SELECT pg_terminate_backend(pg_backend_pid())")
+            .await;
+        let closed = db::DbError::from(client.query("SELECT 1", &[]).await.unwrap_err());
+        assert_eq!(closed.kind(), "connection_closed");
+        assert!(closed.is_closed());
+        assert!(matches!(
+            query_error(closed),
+            AutocompleteError::ServerError
+        ));
+        drop(client);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn an_exhausted_pool_is_told_apart_from_a_failed_query() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        let config = configured(&[("POOL_ACQUIRE_TIMEOUT_MS", "50")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data, &http)).await;
+        let get = || {
+            test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .to_request()
+        };
+        let mut held = Vec::new();
+        for _ in 0..db.pool.status().max_size {
+            held.push(db.pool.get().await.unwrap());
+        }
+        let res = test::call_service(&service, get()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "POOL_EXHAUSTED");
+        held[0]
+            .batch_execute("ALTER TABLE tags RENAME post_count TO posts")
+            .await
+            .unwrap();
+        drop(held);
+        let res = test::call_service(&service, get()).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!res.headers().contains_key(header::RETRY_AFTER));
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "INTERNAL_ERROR");
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn acquiring_from_a_busy_pool_gives_up_in_time() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        let config = configured(&[("POOL_ACQUIRE_TIMEOUT_MS", "50")]);
+        let data = state_with_pool(&config, db.pool.clone());
+        let mut held = Vec::new();
+        for _ in 0..db.pool.status().max_size {
+            held.push(db.pool.get().await.unwrap());
+        }
+        let started = Instant::now();
+        let busy = data.acquire(&data.pool, "primary").await;
+        assert!(matches!(busy, Err(AutocompleteError::PoolExhausted)));
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(50), "{waited:?}");
+        assert!(waited < Duration::from_secs(1), "{waited:?}");
+        held.pop();
+        assert!(data.acquire(&data.pool, "primary").await.is_ok());
+        drop(held);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn a_connection_closed_mid_query_is_retried_once() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0)]).await;
+        let data = Data::new(state_with_pool(&configured(&[]), db.pool.clone()));
+        // room for the search's connections beside these two
+        db.pool.resize(db.pool.status().max_size + 2);
+        let locker = db.pool.get().await.unwrap();
+        // pg_stat_activity holds still inside the locker's transaction
+        let watcher = db.pool.get().await.unwrap();
+        let locker_pid: i32 = locker
+            .query_one("SELECT pg_backend_pid()", &[])
+            .await
+            .unwrap()
+            .get(0);
+        for terminations in [1, 2] {
+            locker
+                .batch_execute("BEGIN; LOCK TABLE tags IN ACCESS EXCLUSIVE MODE")
+                .await
+                .unwrap();
+            let fetching = actix_web::rt::spawn({
+                let data = data.clone();
+                async move {
+                    let search = data.search("fur".to_owned());
+                    fetch(&data, &search, None, None).await.map(|x| x.count)
+                }
+            });
+            // each attempt's connection, once it's stuck behind the lock
+            for _ in 0..terminations {
+                let pid: i32 = loop {
+                    let blocked = watcher
+                        .query_opt(
+                            "SELECT pid FROM pg_stat_activity WHERE $1 = ANY(pg_blocking_pids(pid))",
+                            &[&locker_pid],
+                        )
+                        .await
+                        .unwrap();
+                    if let Some(row) = blocked {
+                        break row.get(0);
+                    }
+                    actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+                };
+                watcher
+                    .execute("SELECT pg_terminate_backend($1)", &[&pid])
+                    .await
+                    .unwrap();
+            }
+            locker.batch_execute("COMMIT").await.unwrap();
+            let fetched = fetching.await.unwrap();
+            match terminations {
+                1 => assert_eq!(fetched.unwrap(), 2),
+                _ => assert!(matches!(fetched, Err(AutocompleteError::ServerError))),
+            }
+        }
+        drop((locker, watcher));
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn startup_retries_the_configured_number_of_times() {
+        let mut pg = deadpool_postgres::Config::new();
+        pg.host = Some("127.0.0.1".to_owned());
+        pg.port = Some(1);
+        pg.dbname = Some("unused".to_owned());
+        let unreachable = pg
+            .create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)
+            .unwrap();
+        // two waits, of 20ms and then 40ms, between three attempts
+        let started = Instant::now();
+        assert!(wait_for_db(&unreachable, 3, Duration::from_millis(20))
+            .await
+            .is_err());
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(60), "{waited:?}");
+        assert!(waited < Duration::from_millis(140), "{waited:?}");
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        assert_eq!(wait_for_db(&db.pool, 1, Duration::ZERO).await, Ok(()));
+        let client = db.pool.get().await.unwrap();
+        let _ = client
+            .batch_execute("SELECT pg_terminate_backend(pg_backend_pid())")
+            .await;
+        drop(client);
+        assert_eq!(
+            wait_for_db(&db.pool, 2, Duration::from_millis(20)).await,
+            Ok(())
+        );
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn errors_carry_a_stable_code() {
+        use actix_web::ResponseError;
+        let cases = [
+            (AutocompleteError::BadRequest, 400, "BAD_REQUEST"),
+            (AutocompleteError::PrefixTooShort, 400, "PREFIX_TOO_SHORT"),
+            (AutocompleteError::PrefixTooLong, 400, "PREFIX_TOO_LONG"),
+            (AutocompleteError::InvalidPrefix, 400, "INVALID_PREFIX"),
+            (AutocompleteError::ServerError, 500, "INTERNAL_ERROR"),
+            (
+                AutocompleteError::ServiceUnavailable,
+                503,
+                "SERVICE_UNAVAILABLE",
+            ),
+            (AutocompleteError::PoolExhausted, 503, "POOL_EXHAUSTED"),
+            (AutocompleteError::Overloaded, 503, "OVERLOADED"),
+            (AutocompleteError::TooManyRequests(3), 429, "RATE_LIMITED"),
+            (AutocompleteError::Unauthorized, 401, "UNAUTHORIZED"),
+            (AutocompleteError::NotFound, 404, "NOT_FOUND"),
+            (AutocompleteError::GatewayTimeout, 504, "QUERY_TIMEOUT"),
+        ];
+        for (error, status, code) in cases {
+            let res = error.error_response();
+            assert_eq!(res.status().as_u16(), status, "{code}");
+            // backing off only helps when the cause is load
+            let retry_after = matches!(
+                error,
+                AutocompleteError::PoolExhausted
+                    | AutocompleteError::Overloaded
+                    | AutocompleteError::TooManyRequests(_)
+            );
+            assert_eq!(
+                res.headers().contains_key(header::RETRY_AFTER),
+                retry_after,
+                "{code}"
+            );
+            let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["code"], code);
+            assert_eq!(body["error"], error.to_string());
+        }
+    }
+
+    #[actix_web::test]
+    async fn validation_failures_get_a_json_400() {
+        use actix_web::test;
+        let (data, http) = server(&[]);
+        let app = test::init_service(app(data, &http)).await;
+        let long = format!("/?search[name_matches]={}", "a".repeat(101));
+        for (uri, code) in [
+            ("/", "BAD_REQUEST"),
+            ("/?search[name_matches]=fur&limit=many", "BAD_REQUEST"),
+            ("/?search[name_matches]=ab", "PREFIX_TOO_SHORT"),
+            (long.as_str(), "PREFIX_TOO_LONG"),
+            ("/?search[name_matches]=f*u*r", "INVALID_PREFIX"),
+        ] {
+            let res =
+                test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "application/json; charset=utf-8"
+            );
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(
+                body,
+                serde_json::json!({ "error": "bad request", "code": code })
+            );
+        }
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(r#"{"limit":5}"#)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "BAD_REQUEST");
+    }
+
+    #[actix_web::test]
+    async fn cors_echoes_only_allowed_origins() {
+        use actix_web::test;
+        let get = |origin: &str| {
+            test::TestRequest::get()
+                .uri("/healthz")
+                .insert_header((header::ORIGIN, origin))
+                .to_request()
+        };
+        let (data, http) = server(&[("ALLOWED_ORIGINS", "https://a.test,https://b.test")]);
+        let service = test::init_service(app(data, &http)).await;
+        let res = test::call_service(&service, get("https://b.test")).await;
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://b.test"
+        );
+        assert!(res.headers().get_all(header::VARY).any(|x| x == "Origin"));
+        let res = test::call_service(&service, get("https://evil.test")).await;
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        assert!(res.headers().get_all(header::VARY).any(|x| x == "Origin"));
+
+        let (data, http) = server(&[]);
+        let service = test::init_service(app(data, &http)).await;
+        let res = test::call_service(&service, get("https://evil.test")).await;
+        assert_eq!(
+            res.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "*"
+        );
+        assert!(!res.headers().get_all(header::VARY).any(|x| x == "Origin"));
+    }
+
+    /// What every backend has to do, whatever it's backed by.
+    async fn check_backend(cache: &dyn CacheBackend) {
+        let data = state(&[]);
+        let fur = data.search("fur".to_owned());
+        let fox = data.search("fox".to_owned());
+        let entry = || CacheEntry::new("[]".to_owned(), 0, None);
+        let miss = cache
+            .get_or_fetch(&fur, Box::pin(async move { Ok((entry(), true)) }))
+            .await
+            .unwrap();
+        assert!(matches!(miss, Lookup::Miss(_)));
+        let hit = cache
+            .get_or_fetch(
+                &fur,
+                Box::pin(async { Err(AutocompleteError::ServerError) }),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(hit, Lookup::Hit(_)));
+        // fetched every time when it's not to be kept
+        for _ in 0..2 {
+            let unkept = cache
+                .get_or_fetch(&fox, Box::pin(async move { Ok((entry(), false)) }))
+                .await
+                .unwrap();
+            assert!(matches!(unkept, Lookup::Miss(_)));
+        }
+        assert!(cache.get(&fox).await.is_none());
+        cache.insert(fox.clone(), entry()).await;
+        assert_eq!(cache.size().await.entries, 2);
+        cache.invalidate(&fox).await;
+        assert!(cache.get(&fox).await.is_none());
+        let other = TagSearch {
+            limit: 5,
+            ..data.search("fur".to_owned())
+        };
+        cache.insert(other.clone(), entry()).await;
+        cache.invalidate_prefix("fur").await.unwrap();
+        assert!(cache.get(&fur).await.is_none());
+        assert!(cache.get(&other).await.is_none());
+        cache.insert(fox.clone(), entry()).await;
+        cache.invalidate_all().await;
+        assert_eq!(cache.size().await.entries, 0);
+    }
+
+    #[actix_web::test]
+    async fn cache_versions_have_their_own_entries() {
+        let (old, new) = (
+            state(&[("CACHE_VERSION", "1")]),
+            state(&[("CACHE_VERSION", "2")]),
+        );
+        let (fur_old, fur_new) = (old.search("fur".to_owned()), new.search("fur".to_owned()));
+        assert!(fur_old != fur_new);
+        let cache = &old.cache;
+        cache
+            .insert(fur_old.clone(), CacheEntry::new("[1]".to_owned(), 1, None))
+            .await;
+        assert!(cache.get(&fur_new).await.is_none());
+        cache
+            .insert(fur_new.clone(), CacheEntry::new("[2]".to_owned(), 1, None))
+            .await;
+        assert_eq!(cache.get(&fur_old).await.unwrap().body, "[1]");
+        assert_eq!(cache.get(&fur_new).await.unwrap().body, "[2]");
+        assert_eq!(cache.size().await.entries, 2);
+    }
+
+    #[actix_web::test]
+    async fn backends_behave_alike() {
+        let config = configured(&[]);
+        let evictions = prometheus::IntCounter::new("evictions", "evictions").unwrap();
+        check_backend(&MokaCache::new(&config, evictions)).await;
+        check_backend(&MockCache::default()).await;
+    }
+
+    #[actix_web::test]
+    async fn concurrent_misses_fetch_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let config = configured(&[]);
+        let evictions = prometheus::IntCounter::new("evictions", "evictions").unwrap();
+        let cache = Arc::new(MokaCache::new(&config, evictions));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fur = state(&[]).search("fur".to_owned());
+        let lookups: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, fetches, fur) = (cache.clone(), fetches.clone(), fur.clone());
+                actix_web::rt::spawn(async move {
+                    let fetch = async {
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        // long enough for the others to arrive and wait
+                        for _ in 0..10 {
+                            actix_web::rt::task::yield_now().await;
+                        }
+                        Ok((CacheEntry::new("[]".to_owned(), 0, None), true))
+                    };
+                    cache.get_or_fetch(&fur, Box::pin(fetch)).await.is_ok()
+                })
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(lookup.await.unwrap());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn stale_entries_are_served_while_they_refresh() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0)]).await;
+        let config = configured(&[("CACHE_SOFT_TTL_SECS", "60")]);
+        let data = Data::new(AutocompleteState {
+            cache: Box::new(MockCache::default()),
+            ..state_with_pool(&config, db.pool.clone())
+        });
+        let search = data.search("fur".to_owned());
+        let stale = CacheEntry {
+            inserted_at: Instant::now() - Duration::from_secs(120),
+            ..CacheEntry::new("[]".to_owned(), 0, None)
+        };
+        data.cache.insert(search.clone(), stale).await;
+        let (entry, hit) = lookup(&data, &search, None).await.unwrap();
+        assert!(hit);
+        assert_eq!(entry.body, "[]");
+        for _ in 0..500 {
+            if data.revalidating.lock().unwrap().is_empty() {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        let refreshed = data.cache.get(&search).await.unwrap();
+        assert_eq!(refreshed.count, 2);
+        assert!(refreshed.inserted_at.elapsed() < Duration::from_secs(60));
+        // fresh now, so served without another refresh
+        let (entry, hit) = lookup(&data, &search, None).await.unwrap();
+        assert!(hit);
+        assert_eq!(entry.body, refreshed.body);
+        assert!(data.revalidating.lock().unwrap().is_empty());
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn searches_fail_over_to_a_replica_that_answers() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0)]).await;
+        let config = configured(&[]);
+        let unreachable = || {
+            let mut pg = deadpool_postgres::Config::new();
+            pg.host = Some("127.0.0.1".to_owned());
+            pg.port = Some(1);
+            pg.dbname = Some("unused".to_owned());
+            pg.create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)
+                .unwrap()
+        };
+        let data = Data::new(AutocompleteState {
+            replicas: vec![unreachable(), db.pool.clone()],
+            ..state_with_pool(&config, unreachable())
+        });
+        // starting from each replica in turn
+        for _ in 0..2 {
+            let entry = fetch(&data, &data.search("fur".to_owned()), None, None)
+                .await
+                .unwrap();
+            assert_eq!(entry.count, 2);
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn lookups_and_purges_go_through_the_backend() {
+        use actix_web::test;
+        let config = configured(&[("ADMIN_TOKEN", "secret")]);
+        let data = Data::new(AutocompleteState {
+            cache: Box::new(MockCache::default()),
+            ..state_for(&config)
+        });
+        let search = data.search("fox".to_owned());
+        let body = r#"[{"name":"fox","post_count":10,"category":5}]"#;
+        data.cache
+            .insert(search.clone(), CacheEntry::new(body.to_owned(), 1, None))
+            .await;
+        // the pool never connects, so this can only come from the backend
+        let (entry, hit) = lookup(&data, &search, None).await.unwrap();
+        assert!(hit);
+        assert_eq!(entry.body, body);
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let req = test::TestRequest::delete()
+            .uri("/cache/fox")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let res = test::call_service(&service, req).await;
+        assert!(res.status().is_success());
+        assert_eq!(data.cache.size().await.entries, 0);
+    }
+
+    #[actix_web::test]
+    async fn purges_need_the_admin_token() {
+        use actix_web::test;
+        let (data, http) = mocked(&[("ADMIN_TOKEN", "secret")]);
+        for prefix in ["fox", "fur"] {
+            cached(&data, data.search(prefix.to_owned()), "[]", 0).await;
+        }
+        data.tag_cache
+            .insert("fox".to_owned(), "{}".to_owned())
+            .await;
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let purge = |uri: &str, token: Option<&str>| {
+            let mut req = test::TestRequest::delete().uri(uri);
+            if let Some(token) = token {
+                req = req.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+            }
+            req.to_request()
+        };
+        for uri in ["/cache", "/cache/fox"] {
+            for token in [None, Some("wrong"), Some("secre")] {
+                let res = test::call_service(&service, purge(uri, token)).await;
+                assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{uri} {token:?}");
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_eq!(body["code"], "UNAUTHORIZED");
+            }
+        }
+        assert_eq!(data.cache.size().await.entries, 2);
+        let res = test::call_service(&service, purge("/cache", Some("secret"))).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(data.cache.size().await.entries, 0);
+        assert!(data.tag_cache.get("fox").await.is_none());
+        // and with no token configured, never
+        let (data, http) = mocked(&[]);
+        let service = test::init_service(app(data, &http)).await;
+        let res = test::call_service(&service, purge("/cache", Some(""))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn warming_caches_the_shortest_prefixes() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[
+            ("furry", 5000, 0),
+            ("fox", 2000, 5),
+            ("ab", 3000, 0),
+            ("wolf", 0, 5),
+        ])
+        .await;
+        let data = Data::new(state_with_pool(&configured(&[]), db.pool.clone()));
+        assert_eq!(warm_cache(&data, 10).await.unwrap(), 2);
+        assert_eq!(data.cache.size().await.entries, 2);
+        for prefix in ["fox", "fur"] {
+            let search = data.search(prefix.to_owned());
+            assert!(data.cache.get(&search).await.is_some(), "{prefix}");
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn etags_are_weak_and_match_either_way() {
+        use actix_web::test;
+        let (data, http) = server(&[("SHORT_PREFIX_EMPTY", "true")]);
+        let service = test::init_service(app(data, &http)).await;
+        let get = |encoding: &str| {
+            test::TestRequest::get()
+                .uri("/?search[name_matches]=ab")
+                .insert_header((header::ACCEPT_ENCODING, encoding))
+                .to_request()
+        };
+        let res = test::call_service(&service, get("gzip")).await;
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+        let res = test::call_service(&service, get("identity")).await;
+        assert_eq!(res.headers().get(header::ETAG).unwrap(), &etag);
+        let strong = etag.to_str().unwrap().trim_start_matches("W/").to_owned();
+        for candidate in [etag.to_str().unwrap(), strong.as_str()] {
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=ab")
+                .insert_header((header::IF_NONE_MATCH, candidate))
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "{candidate}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_results_expire_before_populated_ones() {
+        let cache: Cache<TagSearch, CacheEntry> = Cache::builder()
+            .expire_after(CacheExpiry {
+                ttl: Duration::from_secs(60),
+                empty_ttl: Duration::from_millis(50),
+            })
+            .build();
+        let data = state(&[]);
+        let (empty, populated) = (data.search("xyz".to_owned()), data.search("fur".to_owned()));
+        cache
+            .insert(empty.clone(), CacheEntry::empty(Format::Json))
+            .await;
+        let body = r#"[{"id":1,"name":"fur","post_count":1,"category":0,"antecedent_name":null}]"#;
+        cache
+            .insert(populated.clone(), CacheEntry::new(body.to_owned(), 1, None))
+            .await;
+        assert!(cache.get(&empty).await.is_some());
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.get(&empty).await.is_none());
+        assert!(cache.get(&populated).await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn empty_results_are_cached_briefly_by_clients_too() {
+        use actix_web::test;
+        let (data, http) = mocked(&[("CACHE_EMPTY_TTL_SECS", "60")]);
+        cached(&data, data.search("xyz".to_owned()), "[]", 0).await;
+        cached(
+            &data,
+            data.search("fur".to_owned()),
+            r#"[{"name":"fur"}]"#,
+            1,
+        )
+        .await;
+        let service = test::init_service(app(data, &http)).await;
+        for (prefix, cache_control) in [
+            ("xyz", "public, max-age=60"),
+            ("fur", "public, max-age=604800"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]={prefix}"))
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(header::CACHE_CONTROL).unwrap(),
+                cache_control,
+                "{prefix}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_results_are_204_only_when_asked_for() {
+        use actix_web::test;
+        for (empty_as_204, status, body) in [
+            ("true", StatusCode::NO_CONTENT, ""),
+            ("false", StatusCode::OK, "[]"),
+        ] {
+            let (data, http) = mocked(&[
+                ("EMPTY_AS_204", empty_as_204),
+                ("CACHE_EMPTY_TTL_SECS", "60"),
+            ]);
+            cached(&data, data.search("xyz".to_owned()), "[]", 0).await;
+            cached(
+                &data,
+                data.search("fur".to_owned()),
+                r#"[{"name":"fur"}]"#,
+                1,
+            )
+            .await;
+            let service = test::init_service(app(data.clone(), &http)).await;
+            let get = |prefix: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("/?search[name_matches]={prefix}"))
+                    .to_request()
+            };
+            let res = test::call_service(&service, get("xyz")).await;
+            assert_eq!(res.status(), status, "{empty_as_204}");
+            assert_eq!(
+                res.headers().get(header::CACHE_CONTROL).unwrap(),
+                "public, max-age=60"
+            );
+            assert_eq!(
+                res.headers().contains_key(header::ETAG),
+                status == StatusCode::OK
+            );
+            assert_eq!(test::read_body(res).await, body);
+            // still answered from the cache either way
+            assert_eq!(data.metrics.cache_hits.get(), 1);
+            assert_eq!(data.cache.size().await.entries, 2);
+            let res = test::call_service(&service, get("fur")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+
+    /// Issues a search for each of `prefixes`, then scrapes `/metrics`.
+    async fn scrape(
+        data: Data<AutocompleteState>,
+        http: &HttpSettings,
+        prefixes: &[&str],
+    ) -> String {
+        use actix_web::test;
+        let service = test::init_service(app(data, http)).await;
+        for prefix in prefixes {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]={prefix}"))
+                .to_request();
+            test::call_service(&service, req).await;
+        }
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain; version=0.0.4"));
+        String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn metrics_count_requests_and_cache_lookups() {
+        let (data, http) = mocked(&[]);
+        cached(&data, data.search("fur".to_owned()), "[]", 0).await;
+        let text = scrape(data, &http, &["fur", "fur"]).await;
+        let lines: Vec<_> = text.lines().collect();
+        for sample in [
+            "autocomplete_requests_total 2",
+            "autocomplete_cache_hits_total 2",
+            "autocomplete_cache_misses_total 0",
+            "autocomplete_db_query_duration_seconds_count 0",
+        ] {
+            assert!(lines.contains(&sample), "{sample}");
+        }
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0)]).await;
+        let config = configured(&[]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let text = scrape(data, &http, &["fur", "fur"]).await;
+        let lines: Vec<_> = text.lines().collect();
+        for sample in [
+            "autocomplete_requests_total 2",
+            "autocomplete_cache_hits_total 1",
+            "autocomplete_cache_misses_total 1",
+            "autocomplete_db_query_duration_seconds_count 1",
+        ] {
+            assert!(lines.contains(&sample), "{sample}");
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn latencies_are_bucketed_by_cache_and_status() {
+        // a failed miss beside the hits, for a second series
+        let (data, http) = mocked(&[("PG__HOST", "127.0.0.1"), ("PG__PORT", "1")]);
+        cached(&data, data.search("fur".to_owned()), "[]", 0).await;
+        let text = scrape(data, &http, &["fur", "fox"]).await;
+        let series = |labels: &str| {
+            text.lines()
+                .filter_map(|x| {
+                    x.strip_prefix("autocomplete_request_duration_seconds_bucket{")?
+                        .strip_prefix(labels)?
+                        .strip_prefix(",le=\"")?
+                        .split_once('"')
+                        .map(|(le, _)| le.to_owned())
+                })
+                .collect::<Vec<_>>()
+        };
+        let buckets = [
+            "0.001", "0.002", "0.005", "0.01", "0.025", "0.05", "0.1", "0.25", "0.5", "1", "2.5",
+            "+Inf",
+        ];
+        assert_eq!(series(r#"cache="hit",status="200""#), buckets);
+        assert_eq!(series(r#"cache="miss",status="500""#), buckets);
+        let count = |labels: &str| {
+            let prefix = format!("autocomplete_request_duration_seconds_count{{{labels}}} ");
+            text.lines()
+                .find_map(|x| x.strip_prefix(&prefix))
+                .map(str::to_owned)
+        };
+        assert_eq!(count(r#"cache="hit",status="200""#).as_deref(), Some("1"));
+        assert_eq!(count(r#"cache="miss",status="500""#).as_deref(), Some("1"));
+    }
+
+    #[actix_web::test]
+    async fn readiness_fails_without_the_database() {
+        use actix_web::test;
+        let (data, http) = server(&[("PG__HOST", "127.0.0.1"), ("PG__PORT", "1")]);
+        let service = test::init_service(app(data, &http)).await;
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "SERVICE_UNAVAILABLE");
+        // while liveness doesn't depend on it
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(
+            test::call_service(&service, req).await.status(),
+            StatusCode::OK
+        );
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        let config = configured(&[]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data, &http)).await;
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn limits_default_clamp_and_key_the_cache() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let search = |limit| TagSearch {
+            limit,
+            ..data.search("fur".to_owned())
+        };
+        for limit in [1, 5, DEFAULT_LIMIT, MAX_LIMIT] {
+            cached(&data, search(limit), &format!("[{limit}]"), 1).await;
+        }
+        let service = test::init_service(app(data.clone(), &http)).await;
+        for (query, limit) in [
+            ("", DEFAULT_LIMIT),
+            ("&limit=5", 5),
+            ("&limit=100000", MAX_LIMIT),
+            ("&limit=0", 1),
+            ("&limit=-3", 1),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .to_request();
+            let body = test::call_and_read_body(&service, req).await;
+            assert_eq!(body, format!("[{limit}]"), "{query:?}");
+        }
+        // only ever answered from the four entries
+        assert_eq!(data.cache.size().await.entries, 4);
+    }
+
+    /// The names of the tags in a response.
+    async fn names(res: actix_web::dev::ServiceResponse<impl MessageBody>) -> Vec<String> {
+        assert_eq!(res.status(), StatusCode::OK);
+        let tags: Vec<Tag> = actix_web::test::read_body_json(res).await;
+        tags.into_iter().map(|x| x.name).collect()
+    }
+
+    #[actix_web::test]
+    async fn min_post_count_hides_rare_tags() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0), ("furr", 2, 0)])
+            .await;
+        for (default, expected) in [
+            ("0", ["furry", "fur", "furr"].as_slice()),
+            ("50", &["furry", "fur"]),
+        ] {
+            let config = configured(&[("MIN_POST_COUNT_DEFAULT", default)]);
+            let data = Data::new(state_with_pool(&config, db.pool.clone()));
+            let http = HttpSettings::new(&config, &data);
+            let service = test::init_service(app(data.clone(), &http)).await;
+            let get = |query: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("/?search[name_matches]=fur{query}"))
+                    .to_request()
+            };
+            let res = test::call_service(&service, get("")).await;
+            assert_eq!(names(res).await, expected, "{default}");
+            let res = test::call_service(&service, get("&min_post_count=200")).await;
+            assert_eq!(names(res).await, ["furry"]);
+            // the unfiltered entry is still there, untouched
+            let res = test::call_service(&service, get("")).await;
+            assert_eq!(names(res).await, expected, "{default}");
+            assert_eq!(data.cache.size().await.entries, 2);
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn unused_tags_only_match_when_included() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furless", 0, 0)]).await;
+        for (include, expected) in [("false", ["fur"].as_slice()), ("true", &["fur", "furless"])] {
+            let config = configured(&[("INCLUDE_ZERO_COUNT", include)]);
+            let data = Data::new(state_with_pool(&config, db.pool.clone()));
+            let http = HttpSettings::new(&config, &data);
+            let service = test::init_service(app(data.clone(), &http)).await;
+            for query in ["", "&min_post_count=0", "&min_post_count=-5"] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/?search[name_matches]=fur{query}"))
+                    .to_request();
+                let res = test::call_service(&service, req).await;
+                assert_eq!(names(res).await, expected, "{include}{query}");
+            }
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur&min_post_count=1")
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(names(res).await, ["fur"], "{include}");
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn min_similarity_bounds_the_trigram_fallback() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        // too unlike "wolff" as a whole for stage B, but each has a word like it
+        db.tags(&[
+            ("wolfhound_puppy", 300, 5),
+            ("big_wolf_costume", 20, 0),
+            ("fox", 2000, 5),
+        ])
+        .await;
+        let config = configured(&[("TRIGRAM_FALLBACK", "true")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let get = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=wolff{query}"))
+                .to_request()
+        };
+        let res = test::call_service(&service, get("&min_similarity=0.5")).await;
+        assert_eq!(names(res).await, ["wolfhound_puppy", "big_wolf_costume"]);
+        let res = test::call_service(&service, get("&min_similarity=0.7")).await;
+        assert!(names(res).await.is_empty());
+        assert_eq!(data.cache.size().await.entries, 2);
+        for out_of_range in ["-0.1", "1.5", "NaN"] {
+            let res =
+                test::call_service(&service, get(&format!("&min_similarity={out_of_range}"))).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{out_of_range}");
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn q_is_taken_when_search_name_matches_is_missing() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        cached(&data, data.search("fur".to_owned()), "[\"fur\"]", 1).await;
+        cached(&data, data.search("fox".to_owned()), "[\"fox\"]", 1).await;
+        let service = test::init_service(app(data, &http)).await;
+        for (query, body) in [
+            ("q=fur", "[\"fur\"]"),
+            ("search[name_matches]=fox", "[\"fox\"]"),
+            ("q=fox&search[name_matches]=fur", "[\"fur\"]"),
+            ("search[name_matches]=fox&q=fur", "[\"fox\"]"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?{query}"))
+                .to_request();
+            assert_eq!(
+                test::call_and_read_body(&service, req).await,
+                body,
+                "{query}"
+            );
+        }
+        let req = test::TestRequest::get().uri("/?limit=5").to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn head_answers_like_get_without_a_body() {
+        // the body is dropped by actix's HTTP layer, so this needs a real server
+        let (data, http) = mocked(&[]);
+        let body =
+            r#"[{"id":1,"name":"fur","post_count":100,"category":0,"antecedent_name":null}]"#;
+        cached(&data, data.search("fur".to_owned()), body, 1).await;
+        let server = actix_web::HttpServer::new(move || app(data.clone(), &http))
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        let call = |method: &str, prefix: &str| {
+            let request = format!(
+                "{method} /?search[name_matches]={prefix} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+            );
+            actix_web::rt::task::spawn_blocking(move || {
+                use std::io::{Read, Write};
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                let (head, body) = response.split_once("\r\n\r\n").unwrap();
+                let mut lines = head.lines().map(str::to_owned);
+                let status = lines.next().unwrap();
+                // everything but what differs from one request to the next
+                let headers: Vec<_> = lines
+                    .filter(|x| !x.starts_with("date:") && !x.starts_with("x-request-id:"))
+                    .collect();
+                (status, headers, body.to_owned())
+            })
+        };
+        let get = call("GET", "fur").await.unwrap();
+        let head = call("HEAD", "fur").await.unwrap();
+        assert_eq!(get.0, "HTTP/1.1 200 OK");
+        assert_eq!(get.2, body);
+        assert_eq!((&head.0, &head.1), (&get.0, &get.1));
+        assert!(head.2.is_empty());
+        // validated all the same
+        let head = call("HEAD", "ab").await.unwrap();
+        assert_eq!(head.0, "HTTP/1.1 400 Bad Request");
+        assert!(head.2.is_empty());
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn request_ids_are_echoed_or_generated() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        cached(&data, data.search("fur".to_owned()), "[]", 0).await;
+        let service = test::init_service(app(data, &http)).await;
+        let request_id = |prefix: &str, supplied: Option<&str>| {
+            let mut req = test::TestRequest::get().uri(&format!("/?search[name_matches]={prefix}"));
+            if let Some(supplied) = supplied {
+                req = req.insert_header(("X-Request-Id", supplied));
+            }
+            let req = req.to_request();
+            let service = &service;
+            async move {
+                let res = test::call_service(service, req).await;
+                let id = res.headers().get("x-request-id").unwrap();
+                id.to_str().unwrap().to_owned()
+            }
+        };
+        assert_eq!(request_id("fur", Some("abc-123")).await, "abc-123");
+        // errors carry it too
+        assert_eq!(request_id("ab", Some("abc-123")).await, "abc-123");
+        let oversized = "x".repeat(MAX_REQUEST_ID_LEN + 1);
+        for supplied in [None, Some(""), Some(oversized.as_str())] {
+            let generated = request_id("fur", supplied).await;
+            assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{supplied:?}");
+        }
+        assert_ne!(request_id("fur", None).await, request_id("fur", None).await);
+    }
+
+    #[actix_web::test]
+    async fn cache_control_follows_the_configured_max_age() {
+        use actix_web::test;
+        for (vars, expected) in [
+            (&[][..], "public, max-age=604800"),
+            (&[("RESPONSE_MAX_AGE_SECS", "60")][..], "public, max-age=60"),
+            (
+                &[
+                    ("RESPONSE_MAX_AGE_SECS", "60"),
+                    ("RESPONSE_MAX_AGE_FROM_CACHE_TTL", "true"),
+                    ("CACHE_TTL_SECS", "300"),
+                ][..],
+                "public, max-age=300",
+            ),
+        ] {
+            let (data, http) = mocked(vars);
+            cached(&data, data.search("fur".to_owned()), "[1]", 1).await;
+            let service = test::init_service(app(data, &http)).await;
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.headers().get(header::CACHE_CONTROL).unwrap(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_count_order_is_keyed_by_language() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let search = |order, language: Option<&str>| TagSearch {
+            order,
+            language: language.and_then(Language::negotiate),
+            ..data.search("fur".to_owned())
+        };
+        cached(&data, search(Order::Count, None), "[\"count\"]", 1).await;
+        cached(&data, search(Order::Count, Some("ja")), "[\"count ja\"]", 1).await;
+        cached(&data, search(Order::Name, None), "[\"name\"]", 1).await;
+        let service = test::init_service(app(data.clone(), &http)).await;
+        for (order, language, body) in [
+            ("count", None, "[\"count\"]"),
+            ("count", Some("ja"), "[\"count ja\"]"),
+            ("name", None, "[\"name\"]"),
+            ("name", Some("ja"), "[\"name\"]"),
+            ("name", Some("en"), "[\"name\"]"),
+        ] {
+            let mut req =
+                test::TestRequest::get().uri(&format!("/?search[name_matches]=fur&order={order}"));
+            if let Some(language) = language {
+                req = req.insert_header((header::ACCEPT_LANGUAGE, language));
+            }
+            let served = test::call_and_read_body(&service, req.to_request()).await;
+            assert_eq!(served, body, "{order} {language:?}");
+        }
+        assert_eq!(data.metrics.cache_hits.get(), 5);
+    }
+
+    #[actix_web::test]
+    async fn vary_lists_the_headers_a_response_depended_on() {
+        use actix_web::test;
+        for (vars, query, accept_encoding, expected) in [
+            (&[][..], "", false, &["Accept, Accept-Language"][..]),
+            (&[][..], "&format=json", false, &["Accept-Language"]),
+            (&[][..], "&order=name", false, &["Accept"]),
+            (&[][..], "&format=json&order=name", false, &[]),
+            (
+                &[][..],
+                "&format=json&order=name",
+                true,
+                &["accept-encoding"],
+            ),
+            (
+                &[("ALLOWED_ORIGINS", "https://example.com")][..],
+                "&format=json",
+                false,
+                &["Accept-Language", "Origin"],
+            ),
+        ] {
+            let (data, http) = mocked(vars);
+            for order in [Order::Count, Order::Name] {
+                let search = TagSearch {
+                    order,
+                    ..data.search("fur".to_owned())
+                };
+                cached(&data, search, "[1]", 1).await;
+            }
+            let service = test::init_service(app(data, &http)).await;
+            let mut req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .insert_header((header::ORIGIN, "https://example.com"));
+            if accept_encoding {
+                req = req.insert_header((header::ACCEPT_ENCODING, "gzip"));
+            }
+            let res = test::call_service(&service, req.to_request()).await;
+            let vary: Vec<_> = res
+                .headers()
+                .get_all(header::VARY)
+                .map(|x| x.to_str().unwrap())
+                .collect();
+            assert_eq!(vary, expected, "{vars:?} {query} {accept_encoding}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn match_stage_is_reported_when_enabled() {
+        use actix_web::test;
+        for enabled in [true, false] {
+            let enabled_var = enabled.to_string();
+            let (data, http) = mocked(&[("MATCH_STAGE_HEADER", enabled_var.as_str())]);
+            for (prefix, stage) in [
+                ("fur", Some(db::Stage::A)),
+                ("frry", Some(db::Stage::B)),
+                ("xyz", None),
+            ] {
+                let entry = match stage {
+                    Some(_) => CacheEntry {
+                        stage,
+                        ..CacheEntry::new("[1]".to_owned(), 1, None)
+                    },
+                    None => CacheEntry::empty(Format::Json),
+                };
+                data.cache
+                    .insert(data.search(prefix.to_owned()), entry)
+                    .await;
+            }
+            let service = test::init_service(app(data, &http)).await;
+            for (prefix, expected) in [("fur", "a"), ("frry", "b"), ("xyz", "none")] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/?search[name_matches]={prefix}"))
+                    .to_request();
+                let res = test::call_service(&service, req).await;
+                let stage = res.headers().get("x-match-stage");
+                assert_eq!(stage.is_some(), enabled, "{prefix}");
+                if let Some(stage) = stage {
+                    assert_eq!(stage, expected);
+                }
+            }
+        }
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("furry", 5000, 0), ("fur", 100, 0)]).await;
+        let config = configured(&[("MATCH_STAGE_HEADER", "true")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data, &http)).await;
+        for (prefix, expected) in [("fur", "a"), ("furyy", "b"), ("qqq", "none")] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]={prefix}"))
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(
+                res.headers().get("x-match-stage").unwrap(),
+                expected,
+                "{prefix}"
+            );
+        }
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn if_modified_since_answers_304_from_last_modified() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let entry = CacheEntry {
+            modified,
+            ..CacheEntry::new("[1]".to_owned(), 1, None)
+        };
+        data.cache
+            .insert(data.search("fur".to_owned()), entry)
+            .await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |headers: &[(header::HeaderName, String)]| {
+            let mut req = test::TestRequest::get().uri("/?search[name_matches]=fur");
+            for (name, value) in headers {
+                req = req.insert_header((name.clone(), value.as_str()));
+            }
+            req.to_request()
+        };
+        let res = test::call_service(&service, get(&[])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let last_modified = res.headers().get(header::LAST_MODIFIED).unwrap();
+        assert_eq!(last_modified, "Tue, 14 Nov 2023 22:13:20 GMT");
+        let at = |secs: u64| {
+            let date = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs));
+            (header::IF_MODIFIED_SINCE, date.to_string())
+        };
+        for (since, status) in [
+            (1_700_000_000, StatusCode::NOT_MODIFIED),
+            (1_700_000_001, StatusCode::NOT_MODIFIED),
+            (1_699_999_999, StatusCode::OK),
+        ] {
+            let res = test::call_service(&service, get(&[at(since)])).await;
+            assert_eq!(res.status(), status, "{since}");
+        }
+        // If-None-Match wins when both are sent
+        let stale_etag = (header::IF_NONE_MATCH, "W/\"0\"".to_owned());
+        let res = test::call_service(&service, get(&[at(1_700_000_000), stale_etag])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn security_headers_are_sent_as_configured() {
+        use actix_web::test;
+        let csp = "default-src 'none'";
+        for configured_headers in [false, true] {
+            let vars: &[(&str, &str)] = match configured_headers {
+                true => &[
+                    ("CONTENT_SECURITY_POLICY", csp),
+                    ("REFERRER_POLICY", "no-referrer"),
+                ],
+                false => &[],
+            };
+            let (data, http) = mocked(vars);
+            cached(&data, data.search("fur".to_owned()), "[1]", 1).await;
+            let service = test::init_service(app(data, &http)).await;
+            // on results, errors and probes alike
+            for uri in [
+                "/?search[name_matches]=fur",
+                "/?search[name_matches]=ab",
+                "/healthz",
+            ] {
+                let req = test::TestRequest::get().uri(uri).to_request();
+                let res = test::call_service(&service, req).await;
+                let headers = res.headers();
+                assert_eq!(
+                    headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+                    "nosniff"
+                );
+                let csp_sent = headers.get(header::CONTENT_SECURITY_POLICY);
+                let referrer_policy = headers.get(header::REFERRER_POLICY);
+                match configured_headers {
+                    true => {
+                        assert_eq!(csp_sent.unwrap(), csp, "{uri}");
+                        assert_eq!(referrer_policy.unwrap(), "no-referrer", "{uri}");
+                    }
+                    false => assert!(csp_sent.is_none() && referrer_policy.is_none(), "{uri}"),
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn responses_are_compressed_when_accepted() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let body = format!(
+            "[{}]",
+            vec![r#"{"name":"fur","post_count":100}"#; 50].join(",")
+        );
+        cached(&data, data.search("fur".to_owned()), &body, 50).await;
+        let service = test::init_service(app(data, &http)).await;
+        for (accept_encoding, content_encoding) in [("gzip", Some("gzip")), ("identity", None)] {
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .insert_header((header::ACCEPT_ENCODING, accept_encoding))
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let headers = res.headers();
+            assert_eq!(
+                headers
+                    .get(header::CONTENT_ENCODING)
+                    .map(|x| x.to_str().unwrap()),
+                content_encoding
+            );
+            // the cache's own headers survive compression
+            assert_eq!(
+                headers.get(header::CONTENT_TYPE).unwrap(),
+                "application/json; charset=utf-8"
+            );
+            assert_eq!(
+                headers.get(header::CACHE_CONTROL).unwrap(),
+                "public, max-age=604800"
+            );
+            let sent = test::read_body(res).await;
+            match content_encoding {
+                Some(_) => assert!(sent.len() < body.len() / 4, "{}", sent.len()),
+                None => assert_eq!(sent, body),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn categories_are_cached_apart() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let search = |category| TagSearch {
+            category,
+            ..data.search("fur".to_owned())
+        };
+        assert!(search(None) != search(Some(0)));
+        assert!(search(Some(0)) != search(Some(5)));
+        assert!(search(Some(5)) == search(Some(5)));
+        let fur = r#"[{"id":1,"name":"fur","post_count":100,"category":0,"antecedent_name":null}]"#;
+        let furaffinity = r#"[{"id":2,"name":"furaffinity","post_count":50,"category":5,"antecedent_name":null}]"#;
+        cached(&data, search(Some(0)), fur, 1).await;
+        cached(&data, search(Some(5)), furaffinity, 1).await;
+        let service = test::init_service(app(data.clone(), &http)).await;
+        for (category, body) in [(0, fur), (5, furaffinity)] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur&category={category}"))
+                .to_request();
+            assert_eq!(test::call_and_read_body(&service, req).await, body);
+        }
+        assert_eq!(data.metrics.cache_hits.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn only_allowed_categories_are_searched() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 4), ("furaffinity", 50, 5)])
+            .await;
+        let config = configured(&[("ALLOWED_CATEGORIES", "0,5")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data, &http)).await;
+        let get = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .to_request()
+        };
+        let names = |tags: Vec<Tag>| tags.into_iter().map(|x| x.name).collect::<Vec<_>>();
+        let allowed: Vec<Tag> = test::call_and_read_body_json(&service, get("&category=5")).await;
+        assert_eq!(names(allowed), ["furaffinity"]);
+        for forbidden in ["&category=4", "&category=1"] {
+            let res = test::call_service(&service, get(forbidden)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{forbidden}");
+        }
+        let req = test::TestRequest::get()
+            .uri("/?search[name_matches]=artist:fur")
+            .to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let unfiltered: Vec<Tag> = test::call_and_read_body_json(&service, get("")).await;
+        assert_eq!(names(unfiltered), ["fur", "furaffinity"]);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn nocache_is_only_for_the_admin() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0)]).await;
+        let config = configured(&[("ADMIN_TOKEN", "secret")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let search = data.search("fur".to_owned());
+        cached(&data, search.clone(), "[]", 0).await;
+        let get = |token: Option<&str>| {
+            let mut req = test::TestRequest::get().uri("/?search[name_matches]=fur&nocache=true");
+            if let Some(token) = token {
+                req = req.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+            }
+            req.to_request()
+        };
+        for token in [None, Some("wrong")] {
+            let body = test::call_and_read_body(&service, get(token)).await;
+            assert_eq!(body, "[]", "{token:?}");
+        }
+        let tags: Vec<Tag> = test::call_and_read_body_json(&service, get(Some("secret"))).await;
+        assert_eq!(tags.len(), 2);
+        // and the fresh results replace the cached ones for everyone
+        assert_eq!(data.cache.get(&search).await.unwrap().count, 2);
+        let tags: Vec<Tag> = test::call_and_read_body_json(&service, get(None)).await;
+        assert_eq!(tags.len(), 2);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn tags_are_looked_up_by_exact_name() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fox", 2000, 5), ("foxes", 10, 5)]).await;
+        let config = configured(&[("ADMIN_TOKEN", "secret")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let get = |name: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/tags/{name}"))
+                .to_request()
+        };
+        let res = test::call_service(&service, get("FOX")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let tag: Tag = test::read_body_json(res).await;
+        assert_eq!((tag.name.as_str(), tag.post_count), ("fox", 2000));
+        assert!(data.tag_cache.get("fox").await.is_some());
+        let res = test::call_service(&service, get("fo")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "NOT_FOUND");
+        assert!(data.tag_cache.get("fo").await.is_none());
+        // an edit, then a purge of the prefix, is seen straight away
+        db.pool
+            .get()
+            .await
+            .unwrap()
+            .execute("UPDATE tags SET post_count = 2500 WHERE name = 'fox'", &[])
+            .await
+            .unwrap();
+        let req = test::TestRequest::delete()
+            .uri("/cache/fox")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&service, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let tag: Tag = test::call_and_read_body_json(&service, get("fox")).await;
+        assert_eq!(tag.post_count, 2500);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn tag_lookups_are_bounded_by_max_prefix_len() {
+        use actix_web::test;
+        let (data, http) = server(&[("MAX_PREFIX_LEN", "5")]);
+        let service = test::init_service(app(data, &http)).await;
+        for name in ["abcdef", "abc%20def", "%E2%80%8B"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/tags/{name}"))
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{name}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_results_are_served_but_never_cached() {
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0), ("fox", 10, 0)])
+            .await;
+        let config = configured(&[("MAX_CACHED_BYTES", "100")]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        for _ in 0..2 {
+            let (entry, hit) = lookup(&data, &data.search("fur".to_owned()), None)
+                .await
+                .unwrap();
+            assert_eq!(entry.count, 2);
+            assert!(!hit);
+        }
+        let (_, hit) = lookup(&data, &data.search("fox".to_owned()), None)
+            .await
+            .unwrap();
+        assert!(!hit);
+        let (_, hit) = lookup(&data, &data.search("fox".to_owned()), None)
+            .await
+            .unwrap();
+        assert!(hit);
+        assert_eq!(data.cache.size().await.entries, 1);
+        assert_eq!(data.metrics.cache_misses.get(), 3);
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn streamed_results_match_the_serialized_ones() {
+        let data = state(&[]);
+        let tag = |id, name: &str, antecedent_name: Option<&str>| Tag {
+            id,
+            name: name.to_owned(),
+            post_count: 10 * id,
+            category: 0,
+            antecedent_name: antecedent_name.map(str::to_owned),
+        };
+        let tags = |n| {
+            [
+                (1, "fur", None),
+                (2, "furry", Some("furr")),
+                (3, "furniture", None),
+            ]
+            .into_iter()
+            .take(n)
+            .map(|(id, name, antecedent_name)| tag(id, name, antecedent_name))
+            .collect::<Vec<_>>()
+        };
+        for format in [Format::Json, Format::Ndjson] {
+            let search = TagSearch {
+                format,
+                ..data.search("fur".to_owned())
+            };
+            for n in [0, 1, 3] {
+                let matches = db::Matches {
+                    tags: tags(n),
+                    total: None,
+                    stage: None,
+                    from_stage_a: 0,
+                    timings: Vec::new(),
+                };
+                let vary = Vary {
+                    accept: true,
+                    accept_language: false,
+                };
+                let res = streamed_response(&data, search.clone(), matches, vary);
+                assert_eq!(
+                    res.headers().get(header::CONTENT_TYPE).unwrap(),
+                    format.content_type()
+                );
+                let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+                let body = std::str::from_utf8(&body).unwrap();
+                assert_eq!(body, serialize_results(&search, &tags(n)));
+                let streamed: Vec<Tag> = match format {
+                    Format::Json => serde_json::from_str(body).unwrap(),
+                    _ => body
+                        .lines()
+                        .map(|x| serde_json::from_str(x).unwrap())
+                        .collect(),
+                };
+                let names: Vec<_> = streamed.iter().map(|x| x.name.as_str()).collect();
+                assert_eq!(names, ["fur", "furry", "furniture"][..n]);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn streaming_bypasses_the_cache() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("furry", 5000, 0), ("fox", 10, 0)])
+            .await;
+        let config = configured(&[]);
+        let data = Data::new(state_with_pool(&config, db.pool.clone()));
+        let http = HttpSettings::new(&config, &data);
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let req = test::TestRequest::get()
+            .uri("/?search[name_matches]=fur&stream=true")
+            .to_request();
+        let streamed: Vec<Tag> = test::call_and_read_body_json(&service, req).await;
+        assert_eq!(data.cache.size().await.entries, 0);
+        let req = test::TestRequest::get()
+            .uri("/?search[name_matches]=fur")
+            .to_request();
+        let cached: Vec<Tag> = test::call_and_read_body_json(&service, req).await;
+        let names = |tags: &[Tag]| tags.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&streamed), ["furry", "fur"]);
+        assert_eq!(names(&streamed), names(&cached));
+        for uri in [
+            "/?search[name_matches]=fur&stream=true&format=compact",
+            "/?search[name_matches]=fur&stream=true&group_by_category=true",
+            "/?search[name_matches]=fur&stream=true&callback=f",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+        db.finish().await;
+    }
+
+    #[test]
+    fn pools_are_checked_against_workers_and_queries() {
+        let validate = |vars: &[(&str, &str)]| validate_pool(&configured(vars));
+        assert!(validate(&[("PG_POOL_MAX_SIZE", "0")]).is_err());
+        let clean = [("PG_POOL_MAX_SIZE", "4"), ("WORKERS", "4")];
+        assert_eq!(validate(&clean), Ok(Vec::new()));
+        let warnings = validate(&[
+            ("PG_POOL_MAX_SIZE", "2"),
+            ("WORKERS", "4"),
+            ("MAX_CONCURRENT_QUERIES", "8"),
+        ])
+        .unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("fewer than the 4 workers"));
+        assert!(warnings[1].starts_with("MAX_CONCURRENT_QUERIES (8)"));
+    }
+
+    #[actix_web::test]
+    async fn openapi_is_served_for_the_base_path() {
+        use actix_web::test;
+        let (data, http) = server(&[("BASE_PATH", "/api/")]);
+        let service = test::init_service(app(data, &http)).await;
+        let req = test::TestRequest::get()
+            .uri("/api/openapi.json")
+            .to_request();
+        let document: serde_json::Value = test::call_and_read_body_json(&service, req).await;
+        assert_eq!(document["servers"][0]["url"], "/api");
+    }
+
+    #[actix_web::test]
+    async fn batches_answer_each_prefix_from_the_cache() {
+        use actix_web::test;
+        let (data, http) = mocked(&[("PG__HOST", "127.0.0.1"), ("PG__PORT", "1")]);
+        cached(&data, data.search("fur".to_owned()), "[\"fur\"]", 1).await;
+        cached(&data, data.search("fox".to_owned()), "[\"fox\"]", 1).await;
+        let service = test::init_service(app(data, &http)).await;
+        let post = |prefixes: &[&str]| {
+            test::TestRequest::post()
+                .uri("/batch")
+                .set_json(prefixes)
+                .to_request()
+        };
+        // keyed by what was sent, each once
+        let res = test::call_service(&service, post(&["fur", "FOX", "fur"])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({ "fur": ["fur"], "FOX": ["fox"] }));
+        // a miss goes to the database, which isn't there
+        let res = test::call_service(&service, post(&["fur", "wolf"])).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let too_many = vec!["fur"; MAX_BATCH_SIZE + 1];
+        let res = test::call_service(&service, post(&too_many)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn batches_query_only_the_misses() {
+        use actix_web::test;
+        let Some(db) = db::tests::TestDb::new().await else {
+            return;
+        };
+        db.tags(&[("fur", 100, 0), ("wolf", 1500, 5)]).await;
+        let config = configured(&[]);
+        let data = Data::new(AutocompleteState {
+            cache: Box::new(MockCache::default()),
+            ..state_with_pool(&config, db.pool.clone())
+        });
+        let http = HttpSettings::new(&config, &data);
+        // not what the database has, so a hit can be told from a miss
+        cached(&data, data.search("fur".to_owned()), "[\"cached\"]", 1).await;
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let req = test::TestRequest::post()
+            .uri("/batch")
+            .set_json(["fur", "wolf"])
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&service, req).await;
+        assert_eq!(body["fur"], serde_json::json!(["cached"]));
+        assert_eq!(body["wolf"][0]["name"], "wolf");
+        let wolf = data.cache.get(&data.search("wolf".to_owned())).await;
+        assert_eq!(wolf.map(|x| x.count), Some(1));
+        db.finish().await;
+    }
+
+    #[actix_web::test]
+    async fn post_takes_the_same_filters_as_get() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let search = TagSearch {
+            limit: 5,
+            category: Some(1),
+            order: Order::Name,
+            min_post_count: 10,
+            prefix_only: true,
+            resolve_aliases: true,
+            ..data.search("fur".to_owned())
+        };
+        cached(&data, search, "[\"filtered\"]", 1).await;
+        let service = test::init_service(app(data, &http)).await;
+        let req = test::TestRequest::post()
+            .uri("/")
+            .set_json(serde_json::json!({
+                "prefix": " Fur",
+                "limit": 5,
+                "category": 1,
+                "order": "name",
+                "min_post_count": 10,
+                "prefix_only": true,
+                "resolve_aliases": true,
+            }))
+            .to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "[\"filtered\"]");
+        let req = test::TestRequest::get()
+            .uri(
+                "/?search[name_matches]=fur&limit=5&category=1&order=name\
+                 &min_post_count=10&prefix_only=true&resolve_aliases=true",
+            )
+            .to_request();
+        assert_eq!(
+            test::call_and_read_body(&service, req).await,
+            "[\"filtered\"]"
+        );
+    }
+
+    #[actix_web::test]
+    async fn ndjson_has_one_tag_per_line() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let ndjson = TagSearch {
+            format: Format::Ndjson,
+            ..data.search("fur".to_owned())
+        };
+        found(&data, ndjson, &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let requests = [
+            test::TestRequest::get().uri("/?search[name_matches]=fur&format=ndjson"),
+            test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .insert_header((header::ACCEPT, "application/x-ndjson")),
+        ];
+        for req in requests {
+            let res = test::call_service(&service, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get(header::CONTENT_TYPE).unwrap(),
+                "application/x-ndjson; charset=utf-8"
+            );
+            let body = test::read_body(res).await;
+            let body = std::str::from_utf8(&body).unwrap();
+            assert!(body.ends_with('\n'));
+            let lines = body
+                .lines()
+                .map(|x| serde_json::from_str::<Tag>(x).unwrap().name)
+                .collect::<Vec<_>>();
+            assert_eq!(lines, ["furry", "fox", "fur"]);
+        }
+    }
+
+    #[actix_web::test]
+    async fn fields_project_results_ignoring_unknown_ones() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let projected = |fields: &[&'static str]| TagSearch {
+            fields: Some(fields.to_vec()),
+            ..data.search("fur".to_owned())
+        };
+        let all = ["id", "name", "post_count", "category", "antecedent_name"];
+        found(&data, data.search("fur".to_owned()), &fur_results()).await;
+        found(&data, projected(&["name", "post_count"]), &fur_results()).await;
+        found(&data, projected(&all), &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |fields: &str| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{fields}"))
+                .to_request();
+            let service = &service;
+            async move {
+                let body: serde_json::Value = test::call_and_read_body_json(service, req).await;
+                body
+            }
+        };
+        let keys = |body: &serde_json::Value| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x.as_object().unwrap().keys().cloned().collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let unprojected = get("").await;
+        // in any order, with whatever isn't a field dropped
+        let subset = get("&fields=post_count,name,bogus").await;
+        assert_eq!(keys(&subset), vec![["name", "post_count"]; 3]);
+        assert_eq!(
+            subset[0],
+            serde_json::json!({ "name": "furry", "post_count": 5000 })
+        );
+        assert_eq!(
+            get(&format!("&fields={}", all.join(","))).await,
+            unprojected
+        );
+        // naming nothing known is the same as not asking
+        assert_eq!(get("&fields=bogus").await, unprojected);
+        assert_eq!(keys(&unprojected), vec![all; 3]);
+    }
+
+    #[actix_web::test]
+    async fn debug_wraps_results_in_timings() {
+        use actix_web::test;
+        let entry = CacheEntry {
+            stage: Some(db::Stage::B),
+            ..CacheEntry::new("[\"fur\"]".to_owned(), 1, None)
+        };
+        let diagnostics = Diagnostics {
+            pool: Duration::from_millis(2),
+            stages: vec![
+                (db::Stage::A, Duration::from_millis(3)),
+                (db::Stage::B, Duration::from_micros(4500)),
+            ],
+        };
+        let res = debug_response(&entry, &diagnostics);
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "results": ["fur"],
+                "timings": { "pool_ms": 2.0, "stage_a_ms": 3.0, "stage_b_ms": 4.5 },
+                "stage": "b",
+            })
+        );
+        // only honoured when allowed, and then never from the cache
+        for (allowed, status) in [
+            ("false", StatusCode::OK),
+            ("true", StatusCode::INTERNAL_SERVER_ERROR),
+        ] {
+            let (data, http) = mocked(&[
+                ("ALLOW_DEBUG", allowed),
+                ("PG__HOST", "127.0.0.1"),
+                ("PG__PORT", "1"),
+            ]);
+            cached(&data, data.search("fur".to_owned()), "[\"fur\"]", 1).await;
+            let service = test::init_service(app(data, &http)).await;
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur&debug=true")
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), status, "{allowed}");
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .to_request();
+            assert_eq!(test::call_and_read_body(&service, req).await, "[\"fur\"]");
+        }
+    }
+
+    #[actix_web::test]
+    async fn exact_matches_are_flagged_alongside_the_tag_fields() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let annotated = |prefix: &str| TagSearch {
+            annotate_exact: true,
+            ..data.search(prefix.to_owned())
+        };
+        found(&data, annotated("fur"), &fur_results()).await;
+        found(&data, annotated("furr"), &fur_results()[..1]).await;
+        found(&data, data.search("fur".to_owned()), &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |query: &str| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]={query}"))
+                .to_request();
+            let service = &service;
+            async move {
+                let body: serde_json::Value = test::call_and_read_body_json(service, req).await;
+                body
+            }
+        };
+        let body = get("fur&annotate_exact=true").await;
+        // flattened into the tag rather than wrapping it as {"tag":…,"exact":…}
+        assert_eq!(
+            body[2],
+            serde_json::json!({
+                "id": 3,
+                "name": "fur",
+                "post_count": 100,
+                "category": 0,
+                "antecedent_name": null,
+                "exact": true,
+            })
+        );
+        let exact = |body: &serde_json::Value| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["exact"].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(exact(&body), [false, false, true]);
+        assert_eq!(exact(&get("furr&annotate_exact=true").await), [false]);
+        let body = get("fur").await;
+        assert!(body
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|x| x.get("exact").is_none()));
+    }
+
+    #[actix_web::test]
+    async fn groups_keep_the_ranking_within_each_category() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let grouped = |category_names| TagSearch {
+            group_by_category: true,
+            category_names,
+            ..data.search("fur".to_owned())
+        };
+        found(&data, grouped(false), &fur_results()).await;
+        found(&data, grouped(true), &fur_results()).await;
+        found(&data, data.search("fur".to_owned()), &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |query: &str| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .to_request();
+            let service = &service;
+            async move {
+                let body = test::call_and_read_body(service, req).await;
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+        let names = |body: &serde_json::Value| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        // the raw body, since groups come in the order of their first tag
+        let body = get("&group_by_category=true").await;
+        assert!(body.starts_with("{\"0\":[{"), "{body}");
+        let groups: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(groups.as_object().unwrap().len(), 2);
+        assert_eq!(names(&groups["0"]), ["furry", "fur"]);
+        assert_eq!(names(&groups["5"]), ["fox"]);
+        let body = get("&group_by_category=true&category_names=true").await;
+        let groups: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(names(&groups["general"]), ["furry", "fur"]);
+        assert_eq!(names(&groups["species"]), ["fox"]);
+        // ungrouped is the plain array, unchanged
+        let ungrouped: serde_json::Value = serde_json::from_str(&get("").await).unwrap();
+        assert_eq!(names(&ungrouped), ["furry", "fox", "fur"]);
+        let req = test::TestRequest::get()
+            .uri("/?search[name_matches]=fur&group_by_category=true&format=ndjson")
+            .to_request();
+        let res = test::call_service(&service, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn category_names_only_appear_when_asked_for() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let mut tags = fur_results();
+        // a category with no name to give it
+        tags[0].category = 42;
+        let named = TagSearch {
+            category_names: true,
+            ..data.search("fur".to_owned())
+        };
+        found(&data, named, &tags).await;
+        found(&data, data.search("fur".to_owned()), &tags).await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |query: &str| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .to_request();
+            let service = &service;
+            async move {
+                let body: Vec<serde_json::Value> =
+                    test::call_and_read_body_json(service, req).await;
+                body
+            }
+        };
+        let body = get("&category_names=true").await;
+        let names = body
+            .iter()
+            .map(|x| x.get("category_name"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [None, Some(&"species".into()), Some(&"general".into())]
+        );
+        assert_eq!(body[1]["category"], 5);
+        let body = get("").await;
+        assert!(body.iter().all(|x| x.get("category_name").is_none()));
+    }
+
+    #[actix_web::test]
+    async fn resolved_aliases_show_what_was_matched() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        let resolved = TagSearch {
+            resolve_aliases: true,
+            ..data.search("fur".to_owned())
+        };
+        found(&data, resolved, &fur_results()).await;
+        found(&data, data.search("fur".to_owned()), &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let get = |query: &str| {
+            let req = test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]=fur{query}"))
+                .to_request();
+            let service = &service;
+            async move {
+                let body: Vec<serde_json::Value> =
+                    test::call_and_read_body_json(service, req).await;
+                body
+            }
+        };
+        let body = get("&resolve_aliases=true").await;
+        assert_eq!(body[1]["name"], "fox");
+        assert_eq!(body[1]["matched_name"], "furred_fox");
+        assert_eq!(body[1]["is_alias"], true);
+        assert_eq!(body[0]["matched_name"], "furry");
+        assert_eq!(body[0]["is_alias"], false);
+        // without it, exactly what a `Tag` serializes to
+        let body = get("").await;
+        let tags = serde_json::to_value(fur_results()).unwrap();
+        assert_eq!(serde_json::Value::from(body), tags);
+    }
+
+    #[actix_web::test]
+    async fn autocomplete_path_answers_like_the_root() {
+        use actix_web::test;
+        let (data, http) = mocked(&[]);
+        found(&data, data.search("fur".to_owned()), &fur_results()).await;
+        let service = test::init_service(app(data, &http)).await;
+        let call = |req: test::TestRequest| {
+            let service = &service;
+            async move {
+                let res = test::call_service(service, req.to_request()).await;
+                let status = res.status();
+                let headers = [header::CONTENT_TYPE, header::ETAG, header::CACHE_CONTROL]
+                    .map(|x| res.headers().get(x).cloned());
+                (status, headers, test::read_body(res).await)
+            }
+        };
+        // a hit and a rejected prefix
+        for prefix in ["fur", "ab"] {
+            let get = |path: &str| {
+                test::TestRequest::get().uri(&format!("{path}?search[name_matches]={prefix}"))
+            };
+            assert_eq!(
+                call(get("/autocomplete")).await,
+                call(get("/")).await,
+                "{prefix}"
+            );
+        }
+        for path in ["/", "/autocomplete"] {
+            let post = test::TestRequest::post()
+                .uri(path)
+                .set_json(serde_json::json!({ "prefix": "fur" }));
+            let (status, _, body) = call(post).await;
+            assert_eq!(status, StatusCode::OK, "{path}");
+            let tags: Vec<Tag> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(tags.len(), 3);
+        }
+    }
+
+    #[actix_web::test]
+    async fn only_the_base_path_is_served_unless_probes_stay_at_the_root() {
+        use actix_web::test;
+        for (probes_at_root, probes) in [("false", "/e621"), ("true", "")] {
+            let (data, http) =
+                mocked(&[("BASE_PATH", "/e621"), ("PROBES_AT_ROOT", probes_at_root)]);
+            cached(&data, data.search("fur".to_owned()), "[]", 0).await;
+            let service = test::init_service(app(data, &http)).await;
+            let status = |uri: String| {
+                let req = test::TestRequest::get().uri(&uri).to_request();
+                let service = &service;
+                async move { test::call_service(service, req).await.status() }
+            };
+            let search = "?search[name_matches]=fur";
+            assert_eq!(status(format!("/e621/{search}")).await, StatusCode::OK);
+            assert_eq!(status(format!("/{search}")).await, StatusCode::NOT_FOUND);
+            assert_eq!(
+                status(format!("/autocomplete{search}")).await,
+                StatusCode::NOT_FOUND
+            );
+            let elsewhere = if probes.is_empty() { "/e621" } else { "" };
+            for probe in ["healthz", "metrics"] {
+                let here = status(format!("{probes}/{probe}")).await;
+                assert_eq!(here, StatusCode::OK, "{probes_at_root} {probe}");
+                let there = status(format!("{elsewhere}/{probe}")).await;
+                assert_eq!(there, StatusCode::NOT_FOUND, "{probes_at_root} {probe}");
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn short_prefixes_are_rejected_or_answered_empty() {
+        use actix_web::test;
+        for (empty, status, body) in [
+            (
+                "false",
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "error": "bad request", "code": "PREFIX_TOO_SHORT" }),
+            ),
+            ("true", StatusCode::OK, serde_json::json!([])),
+        ] {
+            let (data, http) = server(&[("SHORT_PREFIX_EMPTY", empty)]);
+            let service = test::init_service(app(data.clone(), &http)).await;
+            for prefix in ["ab", "a", ""] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/?search[name_matches]={prefix}"))
+                    .to_request();
+                let res = test::call_service(&service, req).await;
+                assert_eq!(res.status(), status, "{empty} {prefix:?}");
+                let got: serde_json::Value = test::read_body_json(res).await;
+                assert_eq!(got, body, "{empty} {prefix:?}");
+            }
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=ab&group_by_category=true")
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), status, "{empty}");
+            // answered without so much as a connection
+            assert_eq!(data.pool.status().size, 0);
+        }
+    }
+
+    #[actix_web::test]
+    async fn soft_errors_answer_validation_failures_with_200() {
+        use actix_web::test;
+        let long = format!("/?search[name_matches]={}", "a".repeat(101));
+        let invalid = [
+            ("/?search[name_matches]=ab", "too_short"),
+            (long.as_str(), "invalid"),
+            ("/?search[name_matches]=f*u*r", "invalid"),
+            ("/?search[name_matches]=fur&limit=many", "invalid"),
+            ("/", "invalid"),
+        ];
+        for soft in [false, true] {
+            let (data, http) = mocked(&[
+                ("SOFT_ERRORS", if soft { "true" } else { "false" }),
+                ("PG__HOST", "127.0.0.1"),
+                ("PG__PORT", "1"),
+            ]);
+            let service = test::init_service(app(data, &http)).await;
+            for (uri, code) in invalid {
+                let req = test::TestRequest::get().uri(uri).to_request();
+                let res = test::call_service(&service, req).await;
+                let status = res.status();
+                let body: serde_json::Value = test::read_body_json(res).await;
+                if soft {
+                    assert_eq!(status, StatusCode::OK, "{uri}");
+                    assert_eq!(body, serde_json::json!({ "results": [], "error": code }));
+                } else {
+                    assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+                    assert_eq!(body["error"], "bad request", "{uri}");
+                }
+            }
+            // the database being down is still an error either way
+            let req = test::TestRequest::get()
+                .uri("/?search[name_matches]=fur")
+                .to_request();
+            let res = test::call_service(&service, req).await;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR, "{soft}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn misses_past_the_query_limit_are_shed_but_hits_are_not() {
+        use actix_web::test;
+        let (data, http) = mocked(&[
+            ("MAX_CONCURRENT_QUERIES", "1"),
+            ("QUERY_PERMIT_TIMEOUT_MS", "10"),
+            ("PG__HOST", "127.0.0.1"),
+            ("PG__PORT", "1"),
+        ]);
+        cached(&data, data.search("fur".to_owned()), "[]", 0).await;
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let get = |prefix: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/?search[name_matches]={prefix}"))
+                .to_request()
+        };
+        // as a query in flight would
+        let permit = data.db_permits.as_ref().unwrap().acquire().await.unwrap();
+        let res = test::call_service(&service, get("fox")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = test::call_service(&service, get("fur")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        drop(permit);
+        // on to the database, which isn't there
+        let res = test::call_service(&service, get("fox")).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn evictions_are_counted_but_purges_are_not() {
+        use actix_web::test;
+        let (data, http) = server(&[("CACHE_MAX_CAPACITY", "2")]);
+        for prefix in ["fur", "fox", "wolf", "cat", "dog", "bat"] {
+            cached(&data, data.search(prefix.to_owned()), "[]", 0).await;
+        }
+        let service = test::init_service(app(data.clone(), &http)).await;
+        let stats = || {
+            let req = test::TestRequest::get().uri("/cache/stats").to_request();
+            let service = &service;
+            async move {
+                let stats: serde_json::Value = test::call_and_read_body_json(service, req).await;
+                stats
+            }
+        };
+        let full = stats().await;
+        assert_eq!(full["entry_count"], 2);
+        assert_eq!(full["evictions"], 4);
+        assert_eq!(data.metrics.cache_evictions.get(), 4);
+        data.cache.invalidate(&data.search("fur".to_owned())).await;
+        data.cache.invalidate_all().await;
+        let purged = stats().await;
+        assert_eq!(purged["entry_count"], 0);
+        assert_eq!(purged["evictions"], 4);
+    }
+
+    #[actix_web::test]
+    async fn byte_bounded_caches_evict_by_cumulative_size() {
+        let data = state(&[("CACHE_MAX_BYTES", "1000"), ("CACHE_MAX_CAPACITY", "100")]);
+        let body = |len: usize| format!("[{}]", "1".repeat(len - 2));
+        // weighed by prefix and body: 3 + 97 bytes each
+        for prefix in ["fur", "fox", "cat"] {
+            cached(&data, data.search(prefix.to_owned()), &body(97), 1).await;
+        }
+        let size = data.cache.size().await;
+        assert_eq!((size.entries, size.weight), (3, 300));
+        // well under the entry count, but over the bytes
+        cached(&data, data.search("wolf".to_owned()), &body(796), 1).await;
+        let size = data.cache.size().await;
+        assert!(size.weight <= 1000, "{}", size.weight);
+        assert!(size.entries < 4);
+        assert!(data.metrics.cache_evictions.get() > 0);
+    }
+
+    #[test]
+    fn connections_carry_the_configured_settings() {
+        let configure = |vars: &[(&str, &str)]| {
+            let mut config = configured(vars);
+            configure_connections(&mut config);
+            config.pg
+        };
+        let pg = configure(&[]);
+        assert_eq!(pg.application_name.as_deref(), Some("autocompleted"));
+        assert_eq!(pg.options.as_deref(), Some("-c statement_timeout=3000"));
+        let pg = configure(&[
+            ("APPLICATION_NAME", "autocomplete-eu"),
+            ("STATEMENT_TIMEOUT_MS", "250"),
+            ("PG__OPTIONS", "-c work_mem=8MB"),
+            ("PG_POOL_MAX_SIZE", "9"),
+        ]);
+        assert_eq!(pg.application_name.as_deref(), Some("autocomplete-eu"));
+        assert_eq!(
+            pg.options.as_deref(),
+            Some("-c work_mem=8MB -c statement_timeout=250")
+        );
+        assert_eq!(pg.get_pool_config().max_size, 9);
+        let pg = configure(&[
+            ("APPLICATION_NAME", "autocomplete-eu"),
+            ("PG__APPLICATION_NAME", "explicit"),
+        ]);
+        assert_eq!(pg.application_name.as_deref(), Some("explicit"));
+    }
+
+    #[actix_web::test]
+    async fn connections_identify_themselves_to_the_server() {
+        let Ok(url) = std::env::var("PG_TEST_URL") else {
+            return;
+        };
+        let mut config = configured(&[
+            ("PG__URL", url.as_str()),
+            ("APPLICATION_NAME", "autocomplete-test"),
+            ("STATEMENT_TIMEOUT_MS", "1234"),
+        ]);
+        // the URL names the database
+        config.pg.dbname = None;
+        configure_connections(&mut config);
+        let pool = config
+            .pg
+            .create_pool(Some(Runtime::Tokio1), tokio_postgres::NoTls)
+            .unwrap();
+        let client = pool.get().await.unwrap();
+        let row = client
+            .query_one(
+                "SELECT current_setting('application_name'), current_setting('statement_timeout')",
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "autocomplete-test");
+        assert_eq!(row.get::<_, String>(1), "1234ms");
+    }
+
+    #[actix_web::test]
+    async fn graceful_shutdown_waits_for_requests_in_flight() {
+        let config = configured(&[]);
+        let gate = Arc::new(Semaphore::new(0));
+        let data = Data::new(AutocompleteState {
+            cache: Box::new(Stalled(gate.clone())),
+            ..state_for(&config)
+        });
+        let http = HttpSettings::new(&config, &data);
+        let server = actix_web::HttpServer::new({
+            let data = data.clone();
+            move || app(data.clone(), &http)
+        })
+        .workers(1)
+        .disable_signals()
+        .shutdown_timeout(10)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        let running = actix_web::rt::spawn(server);
+        let held = actix_web::rt::task::spawn_blocking(move || {
+            use std::io::{Read, Write};
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /?search[name_matches]=fur HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        while data.metrics.requests.get() == 0 {
+            actix_web::rt::time::sleep(Duration::from_millis(5)).await;
+        }
+        let stopping = actix_web::rt::spawn(handle.stop(true));
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        assert!(!running.is_finished());
+        // no longer accepting, but the one in flight is still answered
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        gate.add_permits(1);
+        let response = held.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        stopping.await.unwrap();
+        assert!(running.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn the_shutdown_summary_has_the_lifetime_totals() {
+        let metrics = metrics::Metrics::new().unwrap();
+        let summary = ShutdownSummary::new(&metrics);
+        assert_eq!((summary.requests, summary.cache_hit_ratio), (0, 0.0));
+        metrics.requests.inc_by(5);
+        metrics.cache_hits.inc_by(3);
+        metrics.cache_misses.inc();
+        metrics.cache_evictions.inc_by(2);
+        metrics.requests_shed.inc();
+        let summary = ShutdownSummary::new(&metrics);
+        let record = log::Record::builder()
+            .args(format_args!("shutting down"))
+            .level(log::Level::Info)
+            .target("autocompleted")
+            .key_values(&summary)
+            .build();
+        assert_eq!(
+            logging::json_line("2026-10-14T00:00:00Z", &record),
+            serde_json::json!({
+                "ts": "2026-10-14T00:00:00Z",
+                "level": "INFO",
+                "target": "autocompleted",
+                "message": "shutting down",
+                "requests": 5,
+                "cache_hits": 3,
+                "cache_misses": 1,
+                "cache_hit_ratio": 0.75,
+                "cache_evictions": 2,
+                "requests_shed": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn the_access_record_has_the_request_fields() {
+        let access = AccessLog {
+            prefix: "fur".into(),
+            results: 3,
+            cache_hit: false,
+            stage: Some(db::Stage::B),
+            query_time: Duration::from_millis(4),
+        };
+        let record = AccessRecord {
+            request_id: "req-1",
+            client_ip: "10.0.0.1",
+            access: &access,
+            latency: Duration::from_millis(6),
+            status: StatusCode::OK,
+        };
+        let line = log::Record::builder()
+            .args(format_args!("autocomplete"))
+            .level(log::Level::Info)
+            .target("autocompleted::access")
+            .key_values(&record)
+            .build();
+        assert_eq!(
+            logging::json_line("2026-10-14T00:00:00Z", &line),
+            serde_json::json!({
+                "ts": "2026-10-14T00:00:00Z",
+                "level": "INFO",
+                "target": "autocompleted::access",
+                "message": "autocomplete",
+                "request_id": "req-1",
+                "client_ip": "10.0.0.1",
+                "prefix": "fur",
+                "results": 3,
+                "cache_hit": false,
+                "stage": "b",
+                "duration_ms": 4.0,
+                "latency_ms": 6.0,
+                "status": 200,
+            })
+        );
+        // a cache hit that never reached a stage logs an empty one
+        let access = AccessLog {
+            cache_hit: true,
+            ..AccessLog::default()
+        };
+        let record = AccessRecord {
+            access: &access,
+            ..record
+        };
+        let line = log::Record::builder()
+            .target("autocompleted::access")
+            .key_values(&record)
+            .build();
+        let json = logging::json_line("", &line);
+        assert_eq!(
+            (&json["cache_hit"], &json["stage"]),
+            (&true.into(), &"".into())
+        );
+    }
 }